@@ -75,6 +75,9 @@
             pkgs.cacert
             pkgs.curl
             pkgs.git
+            # Shell for the askpass script (see 'Credentials'), and ssh for the repositories with 'ssh_key'.
+            pkgs.bash
+            pkgs.openssh
           ];
           pathsToLink = ["/bin" "/etc"];
         };
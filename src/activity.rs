@@ -0,0 +1,212 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::SystemTime;
+
+// Amount of the most active authors reported in the activity.
+const TOP_AUTHORS: usize = 50;
+
+// Note(andrew): Format of the 'git log' output, parsed by 'Activity::parse'. Every commit starts with the
+//     header line, which begins with '@' (so it can't be confused with '--numstat' lines, that always start
+//     with a digit or '-'), and contains author (with '.mailmap' applied), author time, and author local
+//     time formatted with 'LOG_DATE_FORMAT'.
+pub const LOG_FORMAT: &str = "--format=@%aN%x09%aE%x09%at%x09%ad";
+// Weekday (1 is Monday), hour, and month of the commit, in the timezone of the author.
+pub const LOG_DATE_FORMAT: &str = "--date=format:%u %H %Y-%m";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthorActivity {
+    pub name: String,
+    pub email: String,
+    pub commits: u32,
+    pub added: u64,
+    pub deleted: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct MonthActivity {
+    // Month in the 'YYYY-MM' format.
+    pub month: String,
+    pub commits: u32,
+    pub added: u64,
+    pub deleted: u64,
+}
+
+// Note(andrew): Commit activity of the repository, which is a separate kind of analysis (see 'Storage'),
+//     produced from the whole history of the ref, instead of its latest commit. Added and deleted lines
+//     are coming from 'git log --numstat', so they include every text file (unlike the line counts of
+//     'Data', which only include the languages known to tokei), and merge commits don't have any.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Activity {
+    pub creation_time: u64,
+    pub verified_time: u64,
+    pub repo: String,
+    pub hash: String,
+    pub branch: String,
+    // Same as for the 'Data', see 'Credentials'.
+    #[serde(default)]
+    pub private: bool,
+    // Same as for the 'Data', see 'Freshness'.
+    #[serde(default)]
+    pub size_bytes: u64,
+    pub commits: u32,
+    pub authors: u32,
+    pub first_commit_time: Option<u64>,
+    pub last_commit_time: Option<u64>,
+    // Amount of commits by the weekday (from Monday to Sunday), and by the hour of the day (from 0 to 23).
+    pub by_weekday: Vec<u32>,
+    pub by_hour: Vec<u32>,
+    // Months from the oldest one to the newest one, where months without commits are left out.
+    pub months: Vec<MonthActivity>,
+    // Most active authors, by the amount of commits (see 'TOP_AUTHORS').
+    pub top_authors: Vec<AuthorActivity>,
+}
+
+impl Activity {
+    pub fn new(repo: String, branch: String) -> Self {
+        let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap(); // @UnsafeUnwrap
+
+        Self {
+            creation_time: now.as_secs(),
+            verified_time: now.as_secs(),
+            repo,
+            hash: "".to_string(),
+            branch,
+            private: false,
+            size_bytes: 0,
+            commits: 0,
+            authors: 0,
+            first_commit_time: None,
+            last_commit_time: None,
+            by_weekday: vec![0; 7],
+            by_hour: vec![0; 24],
+            months: Vec::new(),
+            top_authors: Vec::new(),
+        }
+    }
+
+    // Fill the activity from the 'git log' output (see 'LOG_FORMAT'). Lines we can't make sense of are
+    // skipped, since one weird commit is not a reason to throw away the whole history.
+    pub fn parse(&mut self, log: &str) {
+        let mut authors: HashMap<String, AuthorActivity> = HashMap::new();
+        let mut months: BTreeMap<String, MonthActivity> = BTreeMap::new();
+        // Author and month of the current commit, which its '--numstat' lines belong to.
+        let mut current: Option<(String, String)> = None;
+
+        for line in log.lines() {
+            if let Some(header) = line.strip_prefix('@') {
+                current = None;
+                let parts: Vec<&str> = header.split('\t').collect();
+                let (name, email, time, date) = match parts[..] {
+                    [name, email, time, date] => (name, email.to_lowercase(), time, date),
+                    _ => continue,
+                };
+                let time: u64 = match time.parse() {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+                let (weekday, hour, month) = match date.split(' ').collect::<Vec<_>>()[..] {
+                    [weekday, hour, month] => (weekday.parse::<usize>(), hour.parse::<usize>(), month),
+                    _ => continue,
+                };
+
+                self.commits += 1;
+                self.first_commit_time = Some(self.first_commit_time.map_or(time, |t| t.min(time)));
+                self.last_commit_time = Some(self.last_commit_time.map_or(time, |t| t.max(time)));
+                if let Ok(weekday @ 1..=7) = weekday {
+                    self.by_weekday[weekday - 1] += 1;
+                }
+                if let Ok(hour @ 0..=23) = hour {
+                    self.by_hour[hour] += 1;
+                }
+
+                let author = authors.entry(email.clone()).or_insert_with(|| AuthorActivity {
+                    name: name.to_string(),
+                    email: email.clone(),
+                    commits: 0,
+                    added: 0,
+                    deleted: 0,
+                });
+                author.commits += 1;
+
+                let entry = months.entry(month.to_string()).or_insert_with(|| MonthActivity {
+                    month: month.to_string(),
+                    commits: 0,
+                    added: 0,
+                    deleted: 0,
+                });
+                entry.commits += 1;
+
+                current = Some((email, month.to_string()));
+                continue;
+            }
+
+            // Note(andrew): Numstat line is 'added\tdeleted\tpath', where binary files have '-' instead of
+            //     numbers, and are skipped by the parse below.
+            let mut parts = line.split('\t');
+            let (added, deleted) = match (parts.next().map(str::parse::<u64>), parts.next().map(str::parse::<u64>)) {
+                (Some(Ok(added)), Some(Ok(deleted))) => (added, deleted),
+                _ => continue,
+            };
+            if let Some((email, month)) = &current {
+                if let Some(author) = authors.get_mut(email) {
+                    author.added += added;
+                    author.deleted += deleted;
+                }
+                if let Some(entry) = months.get_mut(month) {
+                    entry.added += added;
+                    entry.deleted += deleted;
+                }
+            }
+        }
+
+        self.authors = authors.len() as u32;
+        self.months = months.into_values().collect();
+
+        let mut top: Vec<AuthorActivity> = authors.into_values().collect();
+        top.sort_by(|a, b| b.commits.cmp(&a.commits).then_with(|| a.email.cmp(&b.email)));
+        top.truncate(TOP_AUTHORS);
+        self.top_authors = top;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_numstat_of_binary_files() {
+        let log = "@Andrew\tAndrew@Example.com\t1700000000\t2 14 2023-11\n\
+                   \n\
+                   3\t1\tsrc/main.rs\n\
+                   -\t-\tassets/logo.png\n\
+                   @Bob\tbob@example.com\t1690000000\t7 09 2023-07\n\
+                   \n\
+                   -\t-\tassets/font.woff\n\
+                   -\t-\t{old => new}/icon.ico\n";
+        let mut activity = Activity::new("repo".to_string(), "HEAD".to_string());
+        activity.parse(log);
+
+        assert_eq!(activity.commits, 2);
+        assert_eq!(activity.authors, 2);
+        assert_eq!(activity.first_commit_time, Some(1690000000));
+        assert_eq!(activity.last_commit_time, Some(1700000000));
+        assert_eq!(activity.by_weekday, vec![0, 1, 0, 0, 0, 0, 1]);
+
+        let months: Vec<(&str, u32, u64, u64)> = activity
+            .months
+            .iter()
+            .map(|m| (m.month.as_str(), m.commits, m.added, m.deleted))
+            .collect();
+        assert_eq!(months, vec![("2023-07", 1, 0, 0), ("2023-11", 1, 3, 1)]);
+
+        let authors: Vec<(&str, u64, u64)> = activity
+            .top_authors
+            .iter()
+            .map(|a| (a.email.as_str(), a.added, a.deleted))
+            .collect();
+        assert_eq!(authors, vec![("andrew@example.com", 3, 1), ("bob@example.com", 0, 0)]);
+    }
+}
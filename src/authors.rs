@@ -0,0 +1,152 @@
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::counter::{GitEnv, run_git};
+use crate::data::Data;
+
+// Amount of contributors reported for every language and directory.
+const TOP_AUTHORS: usize = 10;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthorInfo {
+    pub name: String,
+    pub email: String,
+    pub code: u32,
+}
+
+// Top contributors of the group of files (i.e. of the language, or of the directory).
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuthorsInfo {
+    pub name: String,
+    pub authors: Vec<AuthorInfo>,
+}
+
+// Note(andrew): Who wrote the code, which is currently in the repository, according to 'git blame' (with
+//     '.mailmap' of the repository applied). Directories are the top-level ones, where files in the root
+//     of the repository are grouped under '.'.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Authorship {
+    pub languages: Vec<AuthorsInfo>,
+    pub directories: Vec<AuthorsInfo>,
+    // Files, which could not be blamed (e.g. files of the submodules, or files unknown to git).
+    pub skipped_files: u32,
+}
+
+// Lines of the file per author, where author is identified by the email, and has the name attached.
+type Lines = HashMap<String, (String, u32)>;
+
+// Blame the file, counting its lines per author.
+fn blame(repo_path: &str, path: &str, env: &GitEnv) -> Option<Lines> {
+    let output = run_git(env, Some(repo_path), &["blame", "--line-porcelain", "HEAD", "--", path]).ok()?;
+
+    let mut lines = Lines::new();
+    let mut name = "";
+    for line in output.lines() {
+        // Note(andrew): Every blamed line has the header with 'author' and 'author-mail' lines (in that order),
+        //     which are already mapped with '.mailmap' by git. Emails are case-insensitive in practice.
+        if let Some(value) = line.strip_prefix("author ") {
+            name = value;
+        } else if let Some(value) = line.strip_prefix("author-mail ") {
+            let email = value.trim_start_matches('<').trim_end_matches('>').to_lowercase();
+            let entry = lines.entry(email).or_insert_with(|| (name.to_string(), 0));
+            entry.1 += 1;
+        }
+    }
+    Some(lines)
+}
+
+// Note(andrew): Blame only tells us who wrote each line of the file, and doesn't know which of those lines
+//     are code (as opposed to comments and blanks), so code lines of the file are split between authors
+//     in proportion to their lines, where rounding leftovers go to the biggest author. This way code of
+//     the authors adds up to the code of the file.  @Incomplete
+fn split_code(code: u32, lines: &Lines) -> Vec<(String, String, u32)> {
+    let total: u64 = lines.values().map(|(_, count)| *count as u64).sum();
+    if total == 0 {
+        return Vec::new(); // Early return.
+    }
+
+    let mut split: Vec<(String, String, u32)> = lines
+        .iter()
+        .map(|(email, (name, count))| {
+            let share = ((code as u64) * (*count as u64) / total) as u32;
+            (email.clone(), name.clone(), share)
+        })
+        .collect();
+
+    let assigned: u32 = split.iter().map(|(_, _, share)| *share).sum();
+    let biggest = lines
+        .iter()
+        .max_by_key(|(email, (_, count))| (*count, std::cmp::Reverse(*email)));
+    if let Some((email, _)) = biggest
+        && let Some(entry) = split.iter_mut().find(|(e, _, _)| e == email)
+    {
+        entry.2 += code - assigned;
+    }
+
+    split
+}
+
+// Pick top contributors of every group, from the biggest group to the smallest one.
+fn top(groups: HashMap<String, HashMap<String, AuthorInfo>>) -> Vec<AuthorsInfo> {
+    let mut result: Vec<(u64, AuthorsInfo)> = groups
+        .into_iter()
+        .map(|(name, authors)| {
+            let total = authors.values().map(|a| a.code as u64).sum();
+            let mut authors: Vec<AuthorInfo> = authors.into_values().filter(|a| a.code > 0).collect();
+            authors.sort_by(|a, b| b.code.cmp(&a.code).then_with(|| a.email.cmp(&b.email)));
+            authors.truncate(TOP_AUTHORS);
+            (total, AuthorsInfo { name, authors })
+        })
+        .collect();
+
+    result.sort_by(|(a, av), (b, bv)| b.cmp(a).then_with(|| av.name.cmp(&bv.name)));
+    result.into_iter().map(|(_, info)| info).collect()
+}
+
+// Attribute code lines of every counted file to its authors. Requires full history of the repository to be
+// present at the 'repo_path', otherwise every line is attributed to the author of the latest commit.
+pub fn get_authorship(repo_path: &str, data: &Data, env: &GitEnv) -> Authorship {
+    let mut languages: HashMap<String, HashMap<String, AuthorInfo>> = HashMap::new();
+    let mut directories: HashMap<String, HashMap<String, AuthorInfo>> = HashMap::new();
+    let mut skipped_files = 0;
+
+    for lang in data.languages.iter() {
+        for file in lang.files.iter() {
+            let lines = match blame(repo_path, &file.path, env) {
+                Some(value) => value,
+                None => {
+                    skipped_files += 1;
+                    continue;
+                }
+            };
+
+            let directory = match file.path.split_once('/') {
+                Some((first, _)) => first.to_string(),
+                None => ".".to_string(),
+            };
+
+            for (email, name, code) in split_code(file.code, &lines) {
+                for group in [
+                    languages.entry(lang.name.clone()).or_default(),
+                    directories.entry(directory.clone()).or_default(),
+                ] {
+                    let author = group.entry(email.clone()).or_insert_with(|| AuthorInfo {
+                        name: name.clone(),
+                        email: email.clone(),
+                        code: 0,
+                    });
+                    author.code += code;
+                }
+            }
+        }
+    }
+
+    Authorship {
+        languages: top(languages),
+        directories: top(directories),
+        skipped_files,
+    }
+}
@@ -0,0 +1,156 @@
+use crate::data::{Data, Info};
+
+// Colors of the badge value, same as the ones shields.io is using.
+const COLOR_BLUE: &str = "#007ec6";
+const COLOR_GREEN: &str = "#97ca00";
+const COLOR_GREY: &str = "#9f9f9f";
+const COLOR_RED: &str = "#e05d44";
+
+// Note(andrew): Badge of the repository, which has failed to be analyzed (e.g. it doesn't exist), is served
+//     (and cached by the clients) for this amount of seconds, before the analysis is requested again.
+pub const FAILURE_MAX_AGE: u64 = 5 * 60;
+
+// Query of the badge endpoint, where everything is optional.
+#[derive(FromForm, Default, Debug)]
+pub struct BadgeQuery {
+    #[field(name = "ref")]
+    pub target: Option<String>,
+    // Either 'lines' (lines of code, the default one) or 'comment_ratio'.
+    pub metric: Option<String>,
+    // Only count lines of the given language (case-insensitive).
+    pub language: Option<String>,
+}
+
+impl BadgeQuery {
+    pub fn validate(&self) -> Result<(), String> {
+        match self.metric.as_deref() {
+            None | Some("lines") | Some("comment_ratio") => Ok(()),
+            Some(_) => Err("unknown metric".to_string()),
+        }
+    }
+}
+
+pub struct Badge {
+    label: String,
+    value: String,
+    color: &'static str,
+}
+
+impl Badge {
+    pub fn new(label: &str, value: &str, color: &'static str) -> Self {
+        Self {
+            label: label.to_string(),
+            value: value.to_string(),
+            color,
+        }
+    }
+
+    // Badge of the repository, which is not analyzed yet (see 'get_klocc_badge').
+    pub fn pending(query: &BadgeQuery) -> Self {
+        Badge::new(&label(query, query.language.as_deref()), "analyzing", COLOR_GREY)
+    }
+
+    pub fn error(message: &str) -> Self {
+        Badge::new("klocc", message, COLOR_RED)
+    }
+
+    // Build the badge from the analysis result, where the query is already validated.
+    pub fn from_data(data: &Data, query: &BadgeQuery) -> Self {
+        // Label has the name of the language the way tokei spells it, unless it's not in the repository.
+        let (name, info) = match &query.language {
+            Some(name) => match data.languages.iter().find(|l| l.name.eq_ignore_ascii_case(name)) {
+                Some(lang) => (
+                    Some(lang.name.as_str()),
+                    Info::new(lang.total.code, lang.total.comments, lang.total.blanks),
+                ),
+                None => (Some(name.as_str()), Info::default()),
+            },
+            None => (None, Info::new(data.total.code, data.total.comments, data.total.blanks)),
+        };
+
+        match query.metric.as_deref() {
+            Some("comment_ratio") => {
+                let lines = (info.code as u64) + (info.comments as u64);
+                let ratio = match lines {
+                    0 => 0,
+                    _ => ((info.comments as u64) * 100 + lines / 2) / lines,
+                };
+                Badge::new(&label(query, name), &format!("{}%", ratio), COLOR_GREEN)
+            }
+            _ => Badge::new(&label(query, name), &short_number(info.code), COLOR_BLUE),
+        }
+    }
+
+    // Note(andrew): Same layout as the 'flat' style of shields.io. We don't have the font metrics here, so
+    //     width of the text is only estimated, and 'textLength' stretches the text to fill it exactly, which
+    //     looks fine for the short texts we have.
+    pub fn render(&self) -> String {
+        let (label, value) = (escape(&self.label), escape(&self.value));
+        let label_width = text_width(&self.label) + 10;
+        let value_width = text_width(&self.value) + 10;
+        let width = label_width + value_width;
+
+        format!(
+            concat!(
+                r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="20" role="img" aria-label="{l}: {v}">"##,
+                r##"<title>{l}: {v}</title>"##,
+                r##"<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/>"##,
+                r##"<stop offset="1" stop-opacity=".1"/></linearGradient>"##,
+                r##"<clipPath id="r"><rect width="{w}" height="20" rx="3" fill="#fff"/></clipPath>"##,
+                r##"<g clip-path="url(#r)"><rect width="{lw}" height="20" fill="#555"/>"##,
+                r##"<rect x="{lw}" width="{vw}" height="20" fill="{c}"/>"##,
+                r##"<rect width="{w}" height="20" fill="url(#s)"/></g>"##,
+                r##"<g fill="#fff" text-anchor="middle" font-size="11" "##,
+                r##"font-family="Verdana,Geneva,DejaVu Sans,sans-serif">"##,
+                r##"<text x="{lx}" y="15" fill="#010101" fill-opacity=".3" textLength="{lt}">{l}</text>"##,
+                r##"<text x="{lx}" y="14" textLength="{lt}">{l}</text>"##,
+                r##"<text x="{vx}" y="15" fill="#010101" fill-opacity=".3" textLength="{vt}">{v}</text>"##,
+                r##"<text x="{vx}" y="14" textLength="{vt}">{v}</text></g></svg>"##,
+            ),
+            w = width,
+            lw = label_width,
+            vw = value_width,
+            c = self.color,
+            l = label,
+            v = value,
+            lx = (label_width as f32) / 2.0,
+            vx = (label_width as f32) + (value_width as f32) / 2.0,
+            lt = label_width - 10,
+            vt = value_width - 10,
+        )
+    }
+}
+
+fn label(query: &BadgeQuery, language: Option<&str>) -> String {
+    match (query.metric.as_deref(), language) {
+        (Some("comment_ratio"), Some(name)) => format!("{} comment ratio", name),
+        (Some("comment_ratio"), None) => "comment ratio".to_string(),
+        (_, Some(name)) => name.to_string(),
+        (_, None) => "lines of code".to_string(),
+    }
+}
+
+// Format the number the way people write it (e.g. '950', '1.2k', '123k', '4.5M').
+fn short_number(value: u32) -> String {
+    let value = value as f64;
+    match value {
+        v if v < 1e3 => format!("{}", v),
+        v if v < 1e4 => format!("{:.1}k", v / 1e3),
+        v if v < 1e6 => format!("{:.0}k", v / 1e3),
+        v if v < 1e7 => format!("{:.1}M", v / 1e6),
+        v => format!("{:.0}M", v / 1e6),
+    }
+}
+
+// Rough width of the text in pixels, for the 11px Verdana (where most characters are about 7px wide).
+fn text_width(text: &str) -> u32 {
+    text.chars().count() as u32 * 7
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
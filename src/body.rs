@@ -1,8 +1,11 @@
 use rocket::data::{ByteUnit, FromData, Outcome};
 use rocket::http::{ContentType, Status};
-use rocket::serde::{Deserialize, Serialize, json::from_str};
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize, json::from_str};
 use rocket::{Data, Request};
 
+use crate::data::CountOptions;
+use crate::timeline::TimelineOptions;
+
 // Note(andrew): Use this constant as a hard limit for the buffer that reads request
 //     body into memory, since this is more than enough for given arguments, and all
 //     bigger payloads are probably an attempt to pass malicious data or to perform
@@ -16,37 +19,90 @@ pub struct PostJobData {
     pub username: String,
     pub reponame: String,
     pub provider: String,
+    // Optional branch, tag or full commit hash to analyze, instead of the default branch.
+    #[serde(rename = "ref", default)]
+    pub target: Option<String>,
+    // Optional timeline, to count lines at multiple commits of the ref history (see 'TimelineOptions').
+    #[serde(default)]
+    pub timeline: Option<TimelineOptions>,
+    // Optional analysis options (e.g. 'exclude' patterns), which are passed at the top level of the body.
+    #[serde(flatten)]
+    pub options: CountOptions,
+}
+
+// Use this struct as a typed input for the POST /diffs endpoint.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct PostDiffData {
+    pub username: String,
+    pub reponame: String,
+    pub provider: String,
+    // Branches, tags or full commit hashes to compare, where the delta is from 'base' to 'head'.
+    pub base: String,
+    pub head: String,
+    // Optional analysis options, same as for the jobs (see 'PostJobData').
+    #[serde(flatten)]
+    pub options: CountOptions,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PostActivityData {
+    pub username: String,
+    pub reponame: String,
+    pub provider: String,
+    // Branch, tag or full commit hash, whose history is analyzed (see 'PostJobData').
+    #[serde(rename = "ref", default)]
+    pub target: Option<String>,
+}
+
+// This function is called for parsing a body of the request, when we are expecting one of the structs
+// above as a json input for our endpoint. If this function returns 'Outcome::Error', the request fails
+// (or falls through via 'Outcome::Forward'). When this function returns 'Outcome::Success', it means
+// the struct was parsed successfully (e.g. all fields are present) and Rocket framework executes the
+// endpoint code (if all other similar dependencies are resolved successfully as well).
+async fn from_json<'r, T: DeserializeOwned>(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, T, String> {
+    // Ensure the content type is correct before reading the body.
+    let json_ct = ContentType::new("application", "json");
+    // If request does not contain a valid JSON header, forward to the next handler.
+    if req.content_type() != Some(&json_ct) {
+        return Outcome::Forward((data, Status::TemporaryRedirect)); // Early return from the handler.
+    }
+
+    // Reading body up to the 'LIMIT' amount of bytes, and try to read it as utf-8 string.
+    match data.open(LIMIT).into_string().await {
+        // Parsing the struct from the string (as json-like).
+        Ok(string) => match from_str::<T>(&string) {
+            Ok(value) => Outcome::Success(value), // Return successfully.
+            Err(e) => Outcome::Error((Status::BadRequest, format!("Failed to parse json: {}.", e))),
+        },
+        Err(e) => Outcome::Error((Status::BadRequest, format!("Failed to read body: {}.", e))),
+    } // All codepaths return.
 }
 
 #[rocket::async_trait]
 impl<'r> FromData<'r> for PostJobData {
     type Error = String;
 
-    // This function is called for parsing a body of the request, when we are expecting 'PostJobData'
-    // struct as a json input for our endpoint. If this function returns 'Outcome::Error', the request
-    // fails (or falls through via 'Outcome::Forward'). When this function returns 'Outcome::Success', it
-    // means the struct was parsed successfully (e.g. all fields are present) and Rocket framework executes
-    // the endpoint code (if all other similar dependencies are resolved successfully as well).
     async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
-        // Ensure the content type is correct before reading the body.
-        let json_ct = ContentType::new("application", "json");
-        // If request does not contain a valid JSON header, forward to the next handler.
-        if req.content_type() != Some(&json_ct) {
-            return Outcome::Forward((data, Status::TemporaryRedirect)); // Early return from the handler.
-        }
-
-        // Reading body up to the 'LIMIT' amount of bytes, and try to read it as utf-8 string.
-        match data.open(LIMIT).into_string().await {
-            // Parsing 'PostJobData' struct from the string (as json-like).
-            Ok(string) => match from_str::<PostJobData>(&string) {
-                Ok(job) => return Outcome::Success(job), // Return successfully.
-                Err(e) => {
-                    return Outcome::Error((Status::BadRequest, format!("Failed to parse json: {}.", e)));
-                }
-            },
-            Err(e) => {
-                return Outcome::Error((Status::BadRequest, format!("Failed to read body: {}.", e)));
-            }
-        }; // All codepaths return.
+        from_json(req, data).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for PostDiffData {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+        from_json(req, data).await
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromData<'r> for PostActivityData {
+    type Error = String;
+
+    async fn from_data(req: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+        from_json(req, data).await
     }
 }
@@ -0,0 +1,207 @@
+use rocket::serde::json::{from_str, to_pretty_string};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::counter::{GitEnv, get_data_from_repo, get_latest_hash, validate_ref};
+use crate::data::{CountOptions, Data};
+use crate::error::KloccError;
+use crate::limits::Limits;
+use crate::utils::LOG_TO_STDERR;
+
+pub const USAGE: &str = "\
+Usage:
+    klocc [serve]                 Run the server (configured the same way as always, see README).
+    klocc count <path|url> [--ref <ref>] [--options <json>] [--json]
+                                  Count lines of the local git repository or the remote one, and print
+                                  the table (or the json result with '--json').
+
+Options of the 'count' command:
+    --ref <ref>         Branch, tag or full commit hash (default branch if omitted).
+    --options <json>    Counting options, same as in the body of 'POST /api/jobs' (e.g. '{\"exclude\":[\"vendor/\"]}').
+    --json              Print the same json result as the server does, instead of the table.
+";
+
+pub enum Command {
+    Serve,
+    Count(CountArgs),
+    Help,
+}
+
+pub struct CountArgs {
+    // Path to the local repository, or url of the remote one.
+    pub source: String,
+    pub target: String,
+    pub options: CountOptions,
+    pub json: bool,
+}
+
+// Parse arguments of the command line (without the name of the binary itself), where no arguments at all
+// means the server, same as before command line mode was introduced.
+pub fn parse(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Ok(Command::Serve), // Early return.
+    };
+
+    match command {
+        "serve" if rest.is_empty() => Ok(Command::Serve),
+        "serve" => Err(format!(
+            "Unexpected arguments of the 'serve' command: {}",
+            rest.join(" ")
+        )),
+        "count" => parse_count(rest).map(Command::Count),
+        "help" | "--help" | "-h" => Ok(Command::Help),
+        _ => Err(format!("Unknown command '{}'", command)),
+    }
+}
+
+fn parse_count(args: &[String]) -> Result<CountArgs, String> {
+    let mut source = None;
+    let mut target = "HEAD".to_string();
+    let mut options = CountOptions::default();
+    let mut json = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ref" => match args.next() {
+                Some(value) => target = value.clone(),
+                None => return Err("Missing value of '--ref'".to_string()),
+            },
+            "--options" => match args.next() {
+                Some(value) => match from_str::<CountOptions>(value) {
+                    Ok(parsed) => options = parsed,
+                    Err(e) => return Err(format!("Invalid value of '--options': {}", e)),
+                },
+                None => return Err("Missing value of '--options'".to_string()),
+            },
+            "--json" => json = true,
+            value if value.starts_with("--") => return Err(format!("Unknown option '{}'", value)),
+            value if source.is_none() => source = Some(value.to_string()),
+            value => return Err(format!("Unexpected argument '{}'", value)),
+        }
+    }
+
+    let options = options.validate().map_err(|e| e.to_string())?;
+    match source {
+        Some(source) => Ok(CountArgs {
+            source,
+            target,
+            options,
+            json,
+        }),
+        None => Err("Missing path or url of the repository to count".to_string()),
+    }
+}
+
+// Note(andrew): Local repository is cloned the same way as the remote one (from the 'file://' url), so the
+//     numbers are exactly the same as the server would produce for it, which is the whole point of this
+//     mode (e.g. in CI). This also means that only committed files are counted, and not the working tree.
+fn resolve_source(source: &str) -> Result<(String, String), KloccError> {
+    let path = Path::new(source);
+    let repo_url = match path.is_dir() {
+        true => match path.canonicalize() {
+            Ok(value) => format!("file://{}", value.to_string_lossy()),
+            Err(e) => {
+                return Err(KloccError::InvalidRepository(format!(
+                    "Invalid path '{}': {}",
+                    source, e
+                )));
+            }
+        },
+        false => source.to_string(),
+    };
+
+    // Name of the repository is the last segment of the path or url, which only matters for logs.
+    let reponame = repo_url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .unwrap_or_default() // @SafeUnwrap: Split always has at least one item.
+        .trim_end_matches(".git")
+        .to_string();
+    match reponame.is_empty() {
+        true => Err(KloccError::InvalidRepository(format!(
+            "Invalid repository '{}'",
+            source
+        ))),
+        false => Ok((repo_url, reponame)),
+    }
+}
+
+fn count_data(args: &CountArgs) -> Result<Data, KloccError> {
+    let (repo_url, reponame) = resolve_source(&args.source)?;
+    let env = GitEnv::new();
+    validate_ref(&args.target)?;
+
+    let hash = get_latest_hash(repo_url.clone(), args.target.clone(), &env)?;
+    let mut data = get_data_from_repo(
+        "".to_string(),
+        reponame,
+        repo_url,
+        args.target.clone(),
+        &args.options,
+        &env,
+        &Limits::unlimited(),
+        |_| (),
+    )?;
+    data.hash = hash;
+    Ok(data)
+}
+
+fn print_table(data: &Data) {
+    let line = "-".repeat(73);
+    println!("{}", line);
+    println!(
+        " {:<24} {:>11} {:>11} {:>11} {:>11}",
+        "Language", "Files", "Code", "Comments", "Blanks"
+    );
+    println!("{}", line);
+    for lang in data.languages.iter() {
+        println!(
+            " {:<24} {:>11} {:>11} {:>11} {:>11}",
+            lang.name,
+            lang.files.len(),
+            lang.total.code,
+            lang.total.comments,
+            lang.total.blanks
+        );
+        for child in lang.children.iter() {
+            println!(
+                "  |- {:<20} {:>11} {:>11} {:>11} {:>11}",
+                child.name,
+                child.files.len(),
+                child.total.code,
+                child.total.comments,
+                child.total.blanks
+            );
+        }
+    }
+    println!("{}", line);
+    let files: usize = data.languages.iter().map(|lang| lang.files.len()).sum();
+    println!(
+        " {:<24} {:>11} {:>11} {:>11} {:>11}",
+        "Total", files, data.total.code, data.total.comments, data.total.blanks
+    );
+    println!("{}", line);
+}
+
+// Run the 'count' command, returning the exit code of the process.
+pub fn count(args: CountArgs) -> i32 {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+
+    let data = match count_data(&args) {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("{}: {}", e.message_code(), e);
+            return 1;
+        }
+    };
+
+    match args.json {
+        // @SafeUnwrap: Data is plain values, which always serialize into json.
+        true => println!("{}", to_pretty_string(&data).unwrap()),
+        false => print_table(&data),
+    }
+    0
+}
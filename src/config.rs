@@ -0,0 +1,84 @@
+use rocket::figment::Figment;
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use crate::credentials::Credential;
+use crate::freshness::{self, FreshnessTier};
+use crate::limits::Limits;
+
+// Note(andrew): Service specific settings live in the 'klocc' table of the Rocket config, so they
+//     are loaded the same way as the rest of the server configuration (Rocket.toml, or environment
+//     variables with 'ROCKET_' prefix). For example, in the Rocket.toml:
+//
+//         [global.klocc]
+//         workers = 4
+//
+//     or, using environment variable (rocket parses the value as an inline toml table):
+//
+//         ROCKET_KLOCC='{workers=4}'
+//
+//     Every field has a default value, so the whole table (or any field in it) can be omitted.
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Config {
+    // Number of background workers processing analysis jobs concurrently, which is the cap on the number of
+    // analyses (i.e. clones) running at the same time.
+    pub workers: usize,
+    // Number of jobs waiting for the workers, after which new jobs are rejected (see 'KloccError::Overloaded').
+    pub queue_size: usize,
+    // Where analysis results are cached, see 'Storage' implementations.
+    pub storage: StorageKind,
+    // Directory for the 'file' storage, ignored by other kinds of storage.
+    pub storage_dir: PathBuf,
+    // Memory budget of the cache in megabytes, where least recently used entries are evicted when it is
+    // exceeded (see 'Lru'), and zero is unlimited.
+    pub cache_budget_mb: u64,
+    // Additional git service providers (or overrides of built-in ones), mapping provider name to the
+    // url template, e.g. 'forgejo = "https://git.example.com/{username}/{reponame}.git"'.
+    pub providers: HashMap<String, String>,
+    // Server-side credentials for private repositories, keyed by provider name (e.g. 'github') or by
+    // provider name and organization (e.g. 'github/kittyandrew'), see 'Credential' for the fields.
+    pub credentials: HashMap<String, Credential>,
+    // How long cached results stay fresh, depending on the size of the repository (see 'Freshness'). When
+    // configured, it replaces the whole default table.
+    pub freshness: Vec<FreshnessTier>,
+    // Limits of a single analysis (e.g. clone timeout), see 'Limits' for the fields.
+    pub limits: Limits,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum StorageKind {
+    Memory,
+    File,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            queue_size: 100,
+            storage: StorageKind::Memory,
+            storage_dir: PathBuf::from("storage"),
+            cache_budget_mb: 1024,
+            providers: HashMap::new(),
+            credentials: HashMap::new(),
+            freshness: freshness::default_tiers(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+// Extract our config from the rocket figment. Note that we want to crash on startup if the
+// config is present but invalid, as opposed to silently ignoring operator's configuration.
+pub fn load(figment: &Figment) -> Config {
+    if !figment.contains("klocc") {
+        return Config::default(); // Early return, nothing was configured.
+    }
+
+    match figment.extract_inner::<Config>("klocc") {
+        Ok(config) => config,
+        Err(e) => panic!("Invalid 'klocc' configuration: {}", e),
+    }
+}
@@ -1,31 +1,103 @@
-use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
-use tokei::{Config, Languages, Sort};
-
-use crate::data::{Data, FileInfo, Info, LanguageInfo};
-
-// Logging with current unix timestamp. Useful to reduce number of typed lines to do basic logging.
-macro_rules! info {
-    ( $s:tt, $( $x:expr ),* ) => {
-        {
-            let start_time = SystemTime::now();
-            let curr_time  = start_time.duration_since(UNIX_EPOCH).unwrap();  // @UnsafeUnwrap
-            let msg = format!($s, $( $x, )*);
-            println!("[{:>10}] - {}", curr_time.as_secs(), &msg);
-        }
-    };
+use std::cmp::Reverse;
+use std::io::Read;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokei::{CodeStats, Config, Languages, Report, Sort};
+
+use crate::activity::{Activity, LOG_DATE_FORMAT, LOG_FORMAT};
+use crate::authors::get_authorship;
+use crate::data::{CountOptions, Data, FileInfo, Info, LanguageInfo};
+use crate::error::KloccError;
+use crate::exclude::{ExcludedInfo, Exclusions};
+use crate::limits::{Limits, Watch, dir_usage};
+use crate::timeline::Commit;
+
+// How often we are checking whether git is done, while watching it (see 'run_git_limited').
+const GIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Additional environment variables for the git commands (e.g. credentials, see 'Credentials::git_env').
+pub type GitEnv = Vec<(String, String)>;
+
+// Create 'git' command with given environment. Note that we are always disabling interactive prompts,
+// because otherwise git would hang forever waiting for the username of the private repository.
+fn git_command(env: &GitEnv) -> Command {
+    let mut command = Command::new("git");
+    command.env("GIT_TERMINAL_PROMPT", "0");
+    command.envs(env.iter().map(|(k, v)| (k, v)));
+    command
+}
+
+// Check whether given ref is a full commit hash (either sha1 or sha256), as opposed to a branch or tag name.
+pub fn is_commit_hash(target: &str) -> bool {
+    (target.len() == 40 || target.len() == 64) && target.chars().all(|c| c.is_ascii_hexdigit())
 }
 
-pub fn get_latest_hash(repo_url: String, branch: String) -> Result<String, String> {
-    // Call git command to fetch latest hash for the given branch from the remote repository.
-    let output = Command::new("git").args(["ls-remote", &repo_url, &branch]).output();
+// Check that the ref coming from the request is either a full commit hash, or a name git would accept as a ref
+// (rules of 'git check-ref-format --allow-onelevel', e.g. 'main', 'v1.0' or 'refs/pull/1/head').
+//
+// Note(andrew): Besides failing early on the nonsense, this is what keeps the ref from being taken as an option
+//     by git (see 'get_latest_hash'), since no valid ref starts with a dash.
+pub fn validate_ref(target: &str) -> Result<(), KloccError> {
+    let valid = is_commit_hash(target)
+        || (!target.is_empty()
+            && target != "@"
+            && !target.starts_with('-')
+            && !target.starts_with('/')
+            && !target.ends_with('/')
+            && !target.ends_with('.')
+            && !target.contains("..")
+            && !target.contains("//")
+            && !target.contains("@{")
+            && !target
+                .chars()
+                .any(|c| c.is_whitespace() || c.is_control() || "~^:?*[\\".contains(c))
+            && target
+                .split('/')
+                .all(|part| !part.starts_with('.') && !part.ends_with(".lock")));
+
+    match valid {
+        true => Ok(()),
+        false => Err(KloccError::InvalidOptions(format!(
+            "Value '{}' is not a valid ref!",
+            target
+        ))),
+    }
+}
+
+pub fn get_latest_hash(repo_url: String, branch: String, env: &GitEnv) -> Result<String, KloccError> {
+    // Note(andrew): Commit hash is already what we are looking for, and there is no way to ask remote about
+    //     it with 'git ls-remote' anyway (it only lists refs). If such commit doesn't exist, we will find
+    //     out when trying to fetch it.
+    if is_commit_hash(&branch) {
+        return Ok(branch.to_lowercase()); // Early return.
+    }
+
+    // Call git command to fetch latest hash for the given branch from the remote repository. Second pattern
+    // is there to also list the peeled version of the ref, in case it is an annotated tag (see below).
+    //
+    // Note(andrew): Git parses options even after the positional arguments, so the ref coming from the request
+    //     (e.g. '--upload-pack=...') would be taken as an option, and that one runs arbitrary commands for
+    //     local and ssh remotes. Refs are validated by the endpoints (see 'validate_ref'), but every git
+    //     command taking the ref (or the url) is also marking the end of options explicitly.
+    let peeled = format!("{}^{{}}", branch);
+    let output = git_command(env)
+        .args(["ls-remote", "--end-of-options", &repo_url, &branch, &peeled])
+        .output();
 
     // Note(andrew): Confusingly enough, this is an internal rust error for running
     //     command. Meaning, if command ran at all, any exit code will return *result*
     //     here. I guess this can fail only if rust couldn't open a shell process,
     //     or something like that.
     if output.is_err() {
-        return Err(format!("Internal error while executing command: {:?}", output.err()));
+        return Err(KloccError::Internal(format!(
+            "Internal error while executing command: {:?}",
+            output.err()
+        )));
     };
 
     let result = output.unwrap();
@@ -33,82 +105,286 @@ pub fn get_latest_hash(repo_url: String, branch: String) -> Result<String, Strin
     // Note(andrew): Here we are doing an actual check for what status the command
     //     has returned, and return an error from here if the command didn't finish
     //     successfully, where success is defined by whether process returned 0 as
-    //     its exit status code.
+    //     its exit status code. Kind of the error is guessed from the git output.
     if !result.status.success() {
-        return Err(format!(
-            "Failed to fetch latest hash from the remote repository ({}) for the branch '{}': process returned non-zero exit status code.",
+        let context = format!(
+            "Failed to fetch latest hash from the remote repository ({}) for the branch '{}'",
             repo_url, branch
+        );
+        return Err(KloccError::from_git_stderr(
+            &context,
+            &String::from_utf8_lossy(&result.stderr),
         ));
     };
 
     // First extract utf8 string from the stdout..
     let result_string = match String::from_utf8(result.stdout) {
         Ok(value) => value,
-        Err(msg) => return Err(format!("Invalid UTF-8 sequence: {}", msg)),
+        Err(msg) => return Err(KloccError::Internal(format!("Invalid UTF-8 sequence: {}", msg))),
     };
 
-    // ..and then cut first string before '\t', which is a hash in the case of 'git ls-remote'.
-    match result_string.split("\t").next() {
-        Some(v) => Ok(v.to_string()), // Return successfully.
-        None => Err("Failed to split result by '\t' to extract hash from the 'git ls-remote'!".to_string()),
+    // Note(andrew): 'git ls-remote' matches given pattern against the tail of every ref name, so asking for
+    //     'main' might list 'refs/heads/main' along with 'refs/tags/main' or 'refs/heads/old/main'. Output
+    //     is a line per ref, each line is a hash and a ref name, separated by '\t'. Here we are picking the
+    //     exact match in the order git itself would resolve the name, where '^{}' is the peeled annotated
+    //     tag (i.e. hash of the commit that tag points to, instead of the tag object itself).
+    let candidates = [
+        branch.clone(),
+        format!("refs/heads/{}", branch),
+        format!("refs/tags/{}^{{}}", branch),
+        format!("refs/tags/{}", branch),
+    ];
+    let refs: Vec<(&str, &str)> = result_string.lines().filter_map(|line| line.split_once('\t')).collect();
+
+    for candidate in candidates.iter() {
+        if let Some((hash, _)) = refs.iter().find(|(_, name)| name == candidate) {
+            return Ok(hash.to_string()); // Return successfully.
+        }
+    }
+
+    Err(KloccError::RefNotFound(format!(
+        "Failed to fetch latest hash from the remote repository ({}): ref '{}' was not found.",
+        repo_url, branch
+    )))
+}
+
+// Build arguments for the 'git' command to clone given ref of the repository into the 'repo_path'. Shallow
+// clone only has the latest commit, which is all we need to count lines (and is much faster to get).
+fn clone_args(repo_url: &str, branch: &str, repo_path: &str, shallow: bool) -> Vec<String> {
+    let mut args = vec!["clone", "--single-branch", "--recurse-submodules"];
+    if shallow {
+        args.extend(["--depth", "1"]);
     }
+
+    // Note(andrew): 'HEAD' is what we get by default, and 'git clone' only accepts short names of branches
+    //     and tags (e.g. 'main' and not 'refs/heads/main').
+    let short = branch
+        .trim_start_matches("refs/heads/")
+        .trim_start_matches("refs/tags/");
+    if branch != "HEAD" {
+        args.extend(["--branch", short]);
+    }
+
+    args.extend(["--end-of-options", repo_url, repo_path]);
+    args.into_iter().map(String::from).collect()
 }
 
-pub fn get_data_from_repo(_username: String, reponame: String, repo_url: String) -> Result<Data, String> {
-    //let branch   = "master";
-    let branch = "default"; // Not a real branch, just used for logging rn.
+// Run given 'git' command in the 'cwd', returning its output, or an error if it did not finish successfully.
+pub fn run_git(env: &GitEnv, cwd: Option<&str>, args: &[&str]) -> Result<String, KloccError> {
+    let mut command = git_command(env);
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+    let output = command.args(args).output();
 
-    info!("Starting KLOCC procedure for {} ({})", &repo_url, &branch);
+    // Note(andrew): Confusingly enough, this is an internal rust error for running
+    //     command. Meaning, if command ran at all, any exit code will return *result*
+    //     here. I guess this can fail only if rust couldn't open a shell process,
+    //     or something like that.
+    if output.is_err() {
+        return Err(KloccError::Internal(format!(
+            "Internal error while executing command: {:?}",
+            output.err()
+        )));
+    };
+
+    // Note(andrew): Here we are doing an actual check for what status the command
+    //     has returned, and return an error from here if the command didn't finish
+    //     successfully, where success is defined by whether process returned 0 as
+    //     its exit status code. Kind of the error is guessed from the git output.
+    let result = output.unwrap();
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        return Err(KloccError::from_git_stderr("Failed to fetch the repository", &stderr));
+    };
+
+    Ok(String::from_utf8_lossy(&result.stdout).to_string())
+}
+
+// Read the whole pipe of the child process in the background.
+fn drain(pipe: Option<impl Read + Send + 'static>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buffer = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buffer);
+        }
+        buffer
+    })
+}
+
+// Kill git along with all of its helper processes, see 'run_git_limited'.
+fn kill_group(child: &mut Child) {
+    // @Robustness: Group id is the pid of git, since it is the leader of its group, and the group can't go
+    //     away until git is reaped by us (i.e. this can't hit some unrelated group).
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+// Note(andrew): Same as 'run_git', but for the commands fetching (or checking out) the repository, which are
+//     killed as soon as they go over the limits (see 'Limits'). Output is read in the background, since git
+//     would get stuck on the full pipe otherwise, while we are waiting for it to exit.
+fn run_git_limited(env: &GitEnv, cwd: Option<&str>, args: &[&str], watch: &mut Watch) -> Result<String, KloccError> {
+    let mut command = git_command(env);
+    if let Some(cwd) = cwd {
+        command.current_dir(cwd);
+    }
+    command
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Note(andrew): Git does the actual work in its helper processes (e.g. 'git-remote-https', 'index-pack'),
+    //     which would keep on downloading after git itself is killed. So git runs in its own process group,
+    //     which is killed as a whole (see 'kill_group').
+    #[cfg(unix)]
+    command.process_group(0);
+
+    let mut child = match command.spawn() {
+        Ok(value) => value,
+        Err(e) => {
+            return Err(KloccError::Internal(format!(
+                "Internal error while executing command: {:?}",
+                e
+            )));
+        }
+    };
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let status = loop {
+        let exceeded = match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) => watch.check(),
+            Err(e) => Err(KloccError::Internal(format!(
+                "Internal error while executing command: {:?}",
+                e
+            ))),
+        };
+
+        // Note(andrew): Waiting for git to actually exit after killing it, so it doesn't write anything into
+        //     the temporary directory, while the caller is removing it.
+        if let Err(e) = exceeded {
+            kill_group(&mut child);
+            let _ = child.wait();
+            return Err(e);
+        }
+        thread::sleep(GIT_POLL_INTERVAL);
+    };
+
+    // @SafeUnwrap: Reading threads never panic.
+    let stdout = stdout.join().unwrap();
+    let stderr = stderr.join().unwrap();
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        return Err(KloccError::from_git_stderr("Failed to fetch the repository", &stderr));
+    };
+
+    // Size is checked once more, since git might have finished in between the checks.
+    watch.check_usage()?;
+    Ok(String::from_utf8_lossy(&stdout).to_string())
+}
 
+// Phases of the KLOCC procedure, reported to the caller of 'get_data_from_repo' as they start,
+// so the progress of the analysis can be tracked from outside (e.g. by the job queue).
+#[derive(Clone, Copy, Debug)]
+pub enum Phase {
+    Started,
+    Cloning,
+    Counting,
+    CleaningUp,
+}
+
+// Build file info from the tokei report of the file, with given stats (which are either stats of the
+// file itself, or of the code embedded into it).
+fn file_info(report: &Report, stats: &CodeStats, reponame: &str) -> Result<FileInfo, KloccError> {
+    // Note(andrew): Paths in the tokei reports are expected to be valid utf-8 paths of files inside
+    //     our repository directory, and if any of that is not true, we can't produce a meaningful
+    //     report for the file (nor trust the rest of the result), so we fail the whole analysis.
+    let invalid = || KloccError::TokeiFailure(format!("Unexpected path in tokei report: {:?}!", report.name));
+
+    // Store only file name here.
+    let name = match report.name.file_name().and_then(|n| n.to_str()) {
+        Some(value) => value.to_string(),
+        None => return Err(invalid()),
+    };
+    // Convert path buffer item into 'str' first, and then into string for manipulation.
+    let mut path = match report.name.to_str() {
+        Some(value) => value.to_string(),
+        None => return Err(invalid()),
+    };
+    // Note(andrew): Calculating offset of the temp dir as path prefix + repository name + length
+    //     of '/' (which is a last slash, that is present after the repo name). Then use '.drain',
+    //     which consumes in-place 'name' string up to the point of 'offset'. Maybe there is more
+    //     straightforward way to do this in rust std, idk.
+    let offset = match path.find(reponame) {
+        Some(value) => value + reponame.len() + 1,
+        None => return Err(invalid()),
+    };
+    path.drain(..offset);
+
+    Ok(FileInfo::new(
+        name,
+        path,
+        stats.code as u32,
+        stats.comments as u32,
+        stats.blanks as u32,
+    ))
+}
+
+// Create new random temporary directory for the repository, returning the directory (which is removed
+// once dropped) along with the path, where the repository should be cloned into.
+fn temp_repo_dir(reponame: &str) -> Result<(TempDir, String), KloccError> {
     // Generate new random temporary directory.
     let dir = match tempfile::Builder::new().prefix("cloned_repositories").tempdir() {
         Ok(value) => value,
-        Err(e) => return Err(format!("Failed to create temporary directory: {:?}!", e)),
+        Err(e) if e.kind() == std::io::ErrorKind::StorageFull => {
+            return Err(KloccError::DiskQuotaExceeded(format!(
+                "Failed to create temporary directory: {:?}!",
+                e
+            )));
+        }
+        Err(e) => {
+            return Err(KloccError::Internal(format!(
+                "Failed to create temporary directory: {:?}!",
+                e
+            )));
+        }
     };
 
     // Generating full path from the random temporary directory to repository project,
     // using real project name, which we will strip later.  @Speed?
-    let repo_dir = dir.path().join(&reponame);
-    let repo_path = repo_dir.to_str().unwrap();
-
-    info!("Cloning {} ({}) ...", &repo_url, &branch);
-
-    {
-        // This might be worth factoring out in the separate function.
-        let output = Command::new("git")
-            // TODO: Here we always do recurse-submodules, but this can break easily when the submodule is not public.  @Robustness
-            .args([
-                "clone",
-                "--depth",
-                "1",
-                "--single-branch",
-                "--recurse-submodules",
-                &repo_url,
-                repo_path,
-            ])
-            .output();
-
-        // Note(andrew): Confusingly enough, this is an internal rust error for running
-        //     command. Meaning, if command ran at all, any exit code will return *result*
-        //     here. I guess this can fail only if rust couldn't open a shell process,
-        //     or something like that.
-        if output.is_err() {
-            return Err(format!("Internal error while executing command: {:?}", output.err()));
-        };
-
-        // Note(andrew): Here we are doing an actual check for what status the command
-        //     has returned, and return an error from here if the command didn't finish
-        //     successfully, where success is defined by whether process returned 0 as
-        //     its exit status code.
-        if !output.unwrap().status.success() {
-            return Err("Failed to fetch the repository: process returned non-zero exit status code.".to_string());
-        };
-    }
+    let repo_dir = dir.path().join(reponame);
+    let repo_path = match repo_dir.to_str() {
+        Some(value) => value.to_string(),
+        None => {
+            return Err(KloccError::Internal(format!(
+                "Invalid temporary directory path: {:?}!",
+                repo_dir
+            )));
+        }
+    };
 
-    info!("Counting lines for {} ({}) ...", &repo_url, &branch);
+    Ok((dir, repo_path))
+}
 
+// Count lines of the repository checked out at the 'repo_path', producing the analysis result.
+fn count_lines(
+    repo_path: &str,
+    reponame: &str,
+    repo_url: &str,
+    branch: &str,
+    options: &CountOptions,
+) -> Result<Data, KloccError> {
     let included = &[&repo_path]; // The paths to search. Accepts absolute, relative, and glob paths.
-    let excluded = &[]; // Exclude any path that contains any of these strings.
+    let excluded = &[".git"]; // Exclude any path that contains any of these strings.
+    // Note(andrew): Git metadata is never a part of the repository content, but tokei would walk into it
+    //     when hidden files are counted (see 'CountOptions::hidden'). Other than that, we are not giving
+    //     our exclusions to tokei (it only accepts plain patterns anyway), and instead filter its reports
+    //     below, since we want to know how many files were excluded and why (see 'ExcludedInfo'). Patterns
+    //     are read from the repository, so this has to happen after the clone.
     // Note(andrew): Config allows you to configure what is searched and counted. Defaulting all un-filled
     //     fields to default values from the config.
     //     Refer to: https://docs.rs/tokei/12.1.2/tokei/struct.Config.html
@@ -117,8 +393,14 @@ pub fn get_data_from_repo(_username: String, reponame: String, repo_url: String)
     //
     //         sort: Some(Sort::Files)
     //
+    //     Rest of the options are coming from the request (see 'CountOptions').
     let config = Config {
-        treat_doc_strings_as_comments: Some(true),
+        hidden: Some(options.hidden),
+        no_ignore: Some(options.no_ignore),
+        no_ignore_parent: Some(options.no_ignore_parent),
+        no_ignore_vcs: Some(options.no_ignore_vcs),
+        treat_doc_strings_as_comments: Some(options.treat_doc_strings_as_comments),
+        types: options.language_types(),
         ..Config::default()
     };
 
@@ -127,51 +409,92 @@ pub fn get_data_from_repo(_username: String, reponame: String, repo_url: String)
     let mut languages = Languages::new();
     languages.get_statistics(included, excluded, &config);
 
+    let repo_dir = Path::new(repo_path);
+    let exclusions = Exclusions::new(repo_dir, &options.exclude)?;
+    let mut excluded = ExcludedInfo::default();
+
+    for item in languages.values_mut() {
+        // Note(andrew): Tokei only gives us absolute paths of the files, while patterns are relative to
+        //     the repository root (and matcher panics on paths outside of its root), so we strip the
+        //     prefix ourselves and keep any report that we can't make sense of, which fails below.
+        let relative = |path: &Path| path.strip_prefix(repo_dir).map(|p| p.to_path_buf());
+
+        item.reports.retain(|report| match relative(&report.name) {
+            Ok(path) => !exclusions.check(&path, &mut excluded),
+            Err(_) => true,
+        });
+        // Embedded code (e.g. code blocks in markdown) belongs to the files already counted above.
+        for reports in item.children.values_mut() {
+            reports.retain(|report| match relative(&report.name) {
+                Ok(path) => !exclusions.is_excluded(&path),
+                Err(_) => true,
+            });
+        }
+        item.children.retain(|_, reports| !reports.is_empty());
+
+        // Recalculate language stats from the reports that are left.
+        item.total();
+    }
+    languages.retain(|_, item| !item.reports.is_empty() || !item.children.is_empty());
+
     let total = languages.total();
     let mut info = Info::new(total.code as u32, total.comments as u32, total.blanks as u32);
     // Main top-level data structure containing all info that we collect and store.
-    let mut data = Data::new(repo_url.clone(), info);
-
-    // These variables will be used in a language loop.
-    let mut lang: LanguageInfo;
-    let mut file: FileInfo;
-    let mut name: String;
-    let mut path: String;
-    let mut offset: usize;
+    let mut data = Data::new(repo_url.to_string(), branch.to_string(), info);
+    data.excluded = excluded;
+    data.size_bytes = dir_usage(Path::new(repo_path)).0;
+    data.options = options.clone();
 
     for (key, mut item) in languages {
         info = Info::new(item.code as u32, item.comments as u32, item.blanks as u32);
-        lang = LanguageInfo::new(key.to_string(), info);
+        let mut lang = LanguageInfo::new(key.to_string(), info);
 
         // Sorting language reports array by lines of code in each file.  @Speed
         item.sort_by(Sort::Lines);
 
-        for report in item.reports {
-            // Store only file name here.
-            name = report.name.file_name().unwrap().to_str().unwrap().to_string(); // @UnsafeUnwrap
-            // Convert path buffer item into 'str' first, and then into string for manipulation.
-            path = report.name.to_str().unwrap().to_string(); // @UnsafeUnwrap
-            // Note(andrew): Calculating offset of the temp dir as path prefix + repository name + length
-            //     of '/' (which is a last slash, that is present after the repo name). Then use '.drain',
-            //     which consumes in-place 'name' string up to the point of 'offset'. Maybe there is more
-            //     straightforward way to do this in rust std, idk.
-            offset = path.find(&reponame).unwrap() + reponame.len() + 1; // @UnsafeUnwrap
-            path.drain(..offset);
-
-            file = FileInfo::new(
-                name,
-                path,
-                report.stats.code as u32,
-                report.stats.comments as u32,
-                report.stats.blanks as u32,
-            );
+        for report in item.reports.iter() {
+            lang.files.push(file_info(report, &report.stats, reponame)?);
+        }
+
+        // Note(andrew): Children are the languages embedded into files of this language (e.g. rust code
+        //     blocks in markdown, or javascript in html), which tokei doesn't count as part of the language
+        //     itself. Those are listed per language (and per file) here, and are summed up in the separate
+        //     'embedded' total, so the callee can tell documentation apart from the code inside of it.
+        for (child_key, reports) in item.children.iter() {
+            let mut child = LanguageInfo::new(child_key.to_string(), Info::new(0, 0, 0));
+
+            for report in reports {
+                // Embedded code might have its own embedded code (e.g. css inside of html inside of markdown).
+                let stats = report.stats.summarise();
+                child.total.code += stats.code as u32;
+                child.total.comments += stats.comments as u32;
+                child.total.blanks += stats.blanks as u32;
+                child.files.push(file_info(report, &stats, reponame)?);
+            }
 
-            lang.files.push(file);
+            data.embedded.code += child.total.code;
+            data.embedded.comments += child.total.comments;
+            data.embedded.blanks += child.total.blanks;
+
+            child
+                .files
+                .sort_by_key(|f| Reverse((f.code as u64) + (f.comments as u64) + (f.blanks as u64)));
+            lang.children.push(child);
         }
+        lang.children
+            .sort_by_key(|c| Reverse((c.total.code as u64) + (c.total.comments as u64) + (c.total.blanks as u64)));
 
         data.languages.push(lang);
     }
 
+    // Note(andrew): Tokei total includes embedded code, which is what we did from the beginning, so it stays
+    //     the default. Otherwise, embedded code is only reported in the separate total (see above).
+    if !options.include_embedded {
+        data.total.code -= data.embedded.code;
+        data.total.comments -= data.embedded.comments;
+        data.total.blanks -= data.embedded.blanks;
+    }
+
     // Note(andrew): After we inserted all values of 'LanguageInfo' into the 'data', we
     //     can sort them here by total amount of lines of code each language has, since
     //     we are using vector (which allows us to have arbitrary ordered data, instead
@@ -193,9 +516,303 @@ pub fn get_data_from_repo(_username: String, reponame: String, repo_url: String)
         total_b.cmp(&total_a) // This line returns.
     });
 
+    Ok(data)
+}
+
+#[allow(clippy::too_many_arguments)] // Everything the analysis needs to know about the job (see 'JobRequest').
+pub fn get_data_from_repo(
+    _username: String,
+    reponame: String,
+    repo_url: String,
+    branch: String,
+    options: &CountOptions,
+    env: &GitEnv,
+    limits: &Limits,
+    on_phase: impl Fn(Phase),
+) -> Result<Data, KloccError> {
+    info!("Starting KLOCC procedure for {} ({})", &repo_url, &branch);
+    on_phase(Phase::Started);
+
+    let (dir, repo_path) = temp_repo_dir(&reponame)?;
+    let repo_path = repo_path.as_str();
+
+    info!("Cloning {} ({}) ...", &repo_url, &branch);
+    on_phase(Phase::Cloning);
+
+    // Note(andrew): Authorship needs the whole history of the repository for 'git blame' to make sense,
+    //     otherwise every line belongs to whoever made the latest commit.
+    let shallow = !options.authors;
+    let mut watch = limits.watch(repo_path);
+
+    // TODO: Here we always do recurse-submodules, but this can break easily when the submodule is not public.  @Robustness
+    if is_commit_hash(&branch) {
+        // Note(andrew): Commit can't be cloned directly, so instead we are creating an empty repository
+        //     and fetching only that commit into it. This relies on the remote allowing to fetch commits
+        //     by hash, which is the case for major providers (and any server speaking git protocol v2).
+        run_git(env, None, &["init", "--quiet", repo_path])?;
+        let path = Some(repo_path);
+        match shallow {
+            true => run_git_limited(
+                env,
+                path,
+                &["fetch", "--depth", "1", "--end-of-options", &repo_url, &branch],
+                &mut watch,
+            )?,
+            false => run_git_limited(
+                env,
+                path,
+                &["fetch", "--end-of-options", &repo_url, &branch],
+                &mut watch,
+            )?,
+        };
+        run_git_limited(env, path, &["checkout", "--quiet", "FETCH_HEAD"], &mut watch)?;
+        run_git_limited(
+            env,
+            path,
+            &["submodule", "update", "--init", "--recursive", "--depth", "1"],
+            &mut watch,
+        )?;
+    } else {
+        let args = clone_args(&repo_url, &branch, repo_path, shallow);
+        run_git_limited(
+            env,
+            None,
+            &args.iter().map(String::as_str).collect::<Vec<_>>(),
+            &mut watch,
+        )?;
+    }
+
+    info!("Counting lines for {} ({}) ...", &repo_url, &branch);
+    on_phase(Phase::Counting);
+
+    let mut data = count_lines(repo_path, &reponame, &repo_url, &branch, options)?;
+    if options.authors {
+        info!("Blaming files of {} ({}) ...", &repo_url, &branch);
+        data.authorship = Some(get_authorship(repo_path, &data, env));
+    }
+
     info!("Cleaning up after {} ({}) ...", &repo_url, &branch);
+    on_phase(Phase::CleaningUp);
 
-    dir.close().unwrap(); // @UnsafeUnwrap
+    // Note(andrew): Failing to remove temporary directory is not a reason to throw away the result, since
+    //     analysis itself is already done, so we only make some noise about it in the logs.
+    if let Err(e) = dir.close() {
+        info!("Failed to remove temporary directory after {}: {:?}", &repo_url, e);
+    }
 
     Ok(data)
 }
+
+// Note(andrew): Produce commit activity report (see 'Activity') from the whole history of the given ref.
+//     Nothing is checked out, since all we need is 'git log', but blobs have to be fetched anyway for
+//     '--numstat' to work (and fetching them one by one, like with partial clone, would be much slower).
+pub fn get_activity_from_repo(
+    reponame: &str,
+    repo_url: &str,
+    branch: &str,
+    env: &GitEnv,
+    limits: &Limits,
+    on_phase: impl Fn(Phase),
+) -> Result<Activity, KloccError> {
+    info!("Starting activity analysis for {} ({})", repo_url, branch);
+    on_phase(Phase::Started);
+
+    let (dir, repo_path) = temp_repo_dir(reponame)?;
+    let path = Some(repo_path.as_str());
+
+    info!("Fetching history of {} ({}) ...", repo_url, branch);
+    on_phase(Phase::Cloning);
+    let mut watch = limits.watch(&repo_path);
+    run_git(env, None, &["init", "--quiet", &repo_path])?;
+    run_git_limited(
+        env,
+        path,
+        &["fetch", "--quiet", "--no-tags", "--end-of-options", repo_url, branch],
+        &mut watch,
+    )?;
+
+    info!("Reading history of {} ({}) ...", repo_url, branch);
+    on_phase(Phase::Counting);
+    // Note(andrew): Without a checkout there is no '.mailmap' file for git to find, so we point it to the
+    //     one in the fetched commit instead.  @Speed: Whole log is read into memory at once.
+    let log = run_git(
+        env,
+        path,
+        &[
+            "-c",
+            "mailmap.blob=FETCH_HEAD:.mailmap",
+            "log",
+            "--numstat",
+            LOG_FORMAT,
+            LOG_DATE_FORMAT,
+            "FETCH_HEAD",
+        ],
+    )?;
+
+    let mut activity = Activity::new(repo_url.to_string(), branch.to_string());
+    activity.parse(&log);
+    activity.size_bytes = dir_usage(Path::new(&repo_path)).0;
+
+    info!("Cleaning up after {} ({}) ...", repo_url, branch);
+    on_phase(Phase::CleaningUp);
+    if let Err(e) = dir.close() {
+        info!("Failed to remove temporary directory after {}: {:?}", repo_url, e);
+    }
+
+    Ok(activity)
+}
+
+// Note(andrew): Full first-parent history of the target ref, fetched into the temporary directory, so lines
+//     can be counted at any of its commits (see 'timeline'). Blobs are only fetched for the commits that
+//     are checked out, when remote supports partial clone (otherwise remote ignores the filter, and we
+//     just get everything). Temporary directory is removed once this is dropped. Limits (see 'Limits') are
+//     applied to the fetch of the history, and to every checkout separately.
+pub struct History {
+    dir: TempDir,
+    repo_path: String,
+    limits: Limits,
+    // Commits from the tip of the ref back to the very first commit.
+    pub commits: Vec<Commit>,
+}
+
+pub fn fetch_history(
+    reponame: &str,
+    repo_url: &str,
+    branch: &str,
+    env: &GitEnv,
+    limits: &Limits,
+) -> Result<History, KloccError> {
+    info!("Fetching history of {} ({}) ...", repo_url, branch);
+    let (dir, repo_path) = temp_repo_dir(reponame)?;
+    let path = Some(repo_path.as_str());
+    let mut watch = limits.watch(&repo_path);
+
+    // Note(andrew): Filter only works with the named remote (git has to remember where to fetch the
+    //     missing blobs from later), hence 'origin'. Url itself doesn't contain any secrets.
+    run_git(env, None, &["init", "--quiet", &repo_path])?;
+    run_git(env, path, &["remote", "add", "origin", repo_url])?;
+    run_git_limited(
+        env,
+        path,
+        &[
+            "fetch",
+            "--quiet",
+            "--no-tags",
+            "--filter=blob:none",
+            "--end-of-options",
+            "origin",
+            branch,
+        ],
+        &mut watch,
+    )?;
+    let log = run_git(env, path, &["log", "--first-parent", "--format=%H %ct", "FETCH_HEAD"])?;
+
+    let mut commits = Vec::new();
+    for line in log.lines() {
+        let commit = match line.split_once(' ') {
+            Some((hash, time)) => time.parse().ok().map(|time| Commit {
+                hash: hash.to_string(),
+                time,
+            }),
+            None => None,
+        };
+        match commit {
+            Some(value) => commits.push(value),
+            None => return Err(KloccError::Internal(format!("Unexpected line in git log: '{}'!", line))),
+        }
+    }
+
+    Ok(History {
+        dir,
+        repo_path,
+        limits: limits.clone(),
+        commits,
+    })
+}
+
+impl History {
+    // Check out given commit of the history (along with its submodules), and count lines there.
+    pub fn count(
+        &self,
+        reponame: &str,
+        repo_url: &str,
+        hash: &str,
+        options: &CountOptions,
+        env: &GitEnv,
+    ) -> Result<Data, KloccError> {
+        info!("Counting lines for {} ({}) ...", repo_url, hash);
+        let path = Some(self.repo_path.as_str());
+        let mut watch = self.limits.watch(&self.repo_path);
+
+        run_git_limited(env, path, &["checkout", "--quiet", "--force", hash], &mut watch)?;
+        run_git_limited(
+            env,
+            path,
+            &["submodule", "update", "--init", "--recursive", "--depth", "1"],
+            &mut watch,
+        )?;
+
+        let mut data = count_lines(&self.repo_path, reponame, repo_url, hash, options)?;
+        if options.authors {
+            data.authorship = Some(get_authorship(&self.repo_path, &data, env));
+        }
+        Ok(data)
+    }
+
+    pub fn close(self, repo_url: &str) {
+        info!("Cleaning up after {} ...", repo_url);
+        if let Err(e) = self.dir.close() {
+            info!("Failed to remove temporary directory after {}: {:?}", repo_url, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_ref_rejects_options_and_malformed_refs() {
+        let valid = [
+            "HEAD",
+            "main",
+            "v1.0",
+            "release/1.x",
+            "refs/pull/1/head",
+            "0123456789abcdef0123456789abcdef01234567",
+            "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+        ];
+        for target in valid {
+            assert!(validate_ref(target).is_ok(), "{} should be valid", target);
+        }
+
+        let invalid = [
+            "",
+            "-x",
+            "--upload-pack=x",
+            "a..b",
+            "main@{1}",
+            "@{",
+            "@",
+            "a b",
+            "a\tb",
+            "a:b",
+            "a~1",
+            "a^",
+            "a?",
+            "a*",
+            "a[",
+            "a\\b",
+            "/main",
+            "main/",
+            "a//b",
+            "main.",
+            ".hidden",
+            "a/.b",
+            "main.lock",
+        ];
+        for target in invalid {
+            assert!(validate_ref(target).is_err(), "{:?} should be invalid", target);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+use crate::counter::GitEnv;
+
+// Name of the header, where callers pass their access token to be allowed to use server-side credentials.
+const TOKEN_HEADER: &str = "X-Klocc-Token";
+
+// Note(andrew): Script used as 'GIT_ASKPASS', which answers git prompts with values from the environment
+//     of the git command. This way secrets never appear in the command line arguments or in the urls
+//     (which end up in logs and in the cache), and are only given to the git commands that need them.
+//
+//     Prompt has the url git is asking about (e.g. "Password for 'https://user@github.com': "), and only
+//     prompts for the host of the repository itself are answered (see 'origin'), since submodules are
+//     fetched with the same environment, and they can point to any host, which would get the token simply
+//     by asking for the password.
+const ASKPASS_SCRIPT: &str = r#"#!/bin/sh
+url=${1#*\'}
+url=${url%\'*}
+authority=${url#*://}
+authority=${authority%%/*}
+if [ "$url" = "$1" ] || [ "${url%%://*}://${authority##*@}" != "$KLOCC_GIT_ORIGIN" ]; then
+    exit 1
+fi
+case "$1" in
+    Username*) printf '%s\n' "$KLOCC_GIT_USERNAME" ;;
+    *) printf '%s\n' "$KLOCC_GIT_PASSWORD" ;;
+esac
+"#;
+
+// Scheme and host (along with the port) of the url, e.g. 'https://github.com' for 'https://github.com/a/b',
+// which is what the askpass script is comparing the prompt against (see 'ASKPASS_SCRIPT').
+fn origin(url: &str) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let authority = rest.split('/').next().unwrap_or(rest); // @SafeUnwrap: Split always has first item.
+    let host = authority.rsplit('@').next().unwrap_or(authority); // @SafeUnwrap: Same as above.
+    Some(format!("{}://{}", scheme, host))
+}
+
+// Server-side credential for private repositories of the provider (or of the organization).
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Credential {
+    // Username for the https token authentication. Most providers accept anything here, as long as
+    // token is valid, but some need a specific value (e.g. 'x-token-auth' for Bitbucket).
+    #[serde(default = "default_username")]
+    pub username: String,
+    // Deploy (or personal access) token, used as a password for https urls.
+    pub token: Option<String>,
+    // Path to the private ssh key, used for ssh urls (see 'providers' config).
+    pub ssh_key: Option<PathBuf>,
+    // Note(andrew): Callers have to present one of these in the 'X-Klocc-Token' header to be allowed
+    //     to use this credential (and to see results produced with it). Credential without any access
+    //     tokens is never used, since otherwise anyone could read private repositories through us.
+    #[serde(default)]
+    pub access_tokens: Vec<String>,
+}
+
+fn default_username() -> String {
+    "x-access-token".to_string()
+}
+
+// Registry of credentials, keyed by either provider name (e.g. 'github'), or provider name and the
+// organization (e.g. 'github/kittyandrew'), where the latter takes precedence.
+pub struct Credentials {
+    entries: HashMap<String, Credential>,
+    askpass: PathBuf,
+    // Private directory of the askpass script, which is removed once the service stops.
+    _askpass_dir: Option<TempDir>,
+}
+
+impl Credentials {
+    pub fn new(configured: &HashMap<String, Credential>) -> Self {
+        // Note(andrew): Script is only needed when there are tokens configured, and failing to write it
+        //     is fatal, since private repositories would silently stop working otherwise. Script lives in
+        //     the private directory (created with 0700), since the one at the predictable path in the shared
+        //     temporary directory could be planted (or swapped) by any other user of the machine, and git
+        //     would run it with our tokens in the environment.
+        let mut askpass = PathBuf::new();
+        let mut askpass_dir = None;
+        if configured.values().any(|c| c.token.is_some()) {
+            let mut builder = tempfile::Builder::new();
+            builder.prefix("klocc-askpass");
+            #[cfg(unix)]
+            builder.permissions(fs::Permissions::from_mode(0o700));
+            let dir = match builder.tempdir() {
+                Ok(value) => value,
+                Err(e) => panic!("Failed to create directory for askpass script: {}", e),
+            };
+            askpass = dir.path().join("askpass.sh");
+            if let Err(e) = fs::write(&askpass, ASKPASS_SCRIPT) {
+                panic!("Failed to write askpass script to {:?}: {}", &askpass, e);
+            }
+            #[cfg(unix)]
+            if let Err(e) = fs::set_permissions(&askpass, fs::Permissions::from_mode(0o700)) {
+                panic!("Failed to make askpass script at {:?} executable: {}", &askpass, e);
+            }
+            askpass_dir = Some(dir);
+        }
+
+        Self {
+            entries: configured.clone(),
+            askpass,
+            _askpass_dir: askpass_dir,
+        }
+    }
+
+    // Find the name of the credential for the given repository owner, if caller is allowed to use it.
+    pub fn authorize(&self, provider: &str, username: &str, caller: &CallerToken) -> Option<String> {
+        // Note(andrew): Organization is the first segment of the username, since for the nested
+        //     namespaces (e.g. GitLab subgroups) it is still the top-level group who owns the tokens.
+        let organization = username.split('/').next().unwrap_or(username); // @SafeUnwrap: Split always has first item.
+        let names = [format!("{}/{}", provider, organization), provider.to_string()];
+
+        names
+            .into_iter()
+            .find(|name| self.entries.contains_key(name))
+            .filter(|name| self.is_authorized(name, caller))
+    }
+
+    // Check whether caller is allowed to use the credential (and see results produced with it).
+    pub fn is_authorized(&self, name: &str, caller: &CallerToken) -> bool {
+        match (self.entries.get(name), &caller.0) {
+            (Some(credential), Some(token)) => credential.access_tokens.iter().any(|t| t == token),
+            _ => false,
+        }
+    }
+
+    // Environment for the git commands, which makes them use given credential for the given repository.
+    pub fn git_env(&self, name: &str, repo_url: &str) -> GitEnv {
+        let mut env = GitEnv::new();
+        let credential = match self.entries.get(name) {
+            Some(value) => value,
+            None => return env,
+        };
+
+        if let Some(token) = &credential.token {
+            env.push(("GIT_ASKPASS".to_string(), self.askpass.to_string_lossy().to_string()));
+            env.push(("KLOCC_GIT_ORIGIN".to_string(), origin(repo_url).unwrap_or_default()));
+            env.push(("KLOCC_GIT_USERNAME".to_string(), credential.username.clone()));
+            env.push(("KLOCC_GIT_PASSWORD".to_string(), token.clone()));
+        }
+
+        if let Some(key) = &credential.ssh_key {
+            let command = format!(
+                "ssh -i '{}' -o IdentitiesOnly=yes -o BatchMode=yes",
+                key.to_string_lossy()
+            );
+            env.push(("GIT_SSH_COMMAND".to_string(), command));
+        }
+
+        env
+    }
+}
+
+// Access token presented by the caller (see 'TOKEN_HEADER'), if any.
+pub struct CallerToken(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for CallerToken {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let token = req.headers().get_one(TOKEN_HEADER).map(String::from);
+        Outcome::Success(CallerToken(token)) // Never fails, since token is optional.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn origin_is_scheme_and_host_of_the_url() {
+        assert_eq!(origin("https://github.com/a/b").as_deref(), Some("https://github.com"));
+        assert_eq!(
+            origin("https://user@gitlab.example.com:8443/a/b/c").as_deref(),
+            Some("https://gitlab.example.com:8443")
+        );
+        assert_eq!(origin("https://github.com").as_deref(), Some("https://github.com"));
+        assert_eq!(origin("git@github.com:a/b.git"), None);
+    }
+}
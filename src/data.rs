@@ -1,9 +1,19 @@
-use rocket::serde::Serialize;
+use rocket::serde::json::to_string;
+use rocket::serde::{Deserialize, Serialize};
 use rocket::tokio::sync::Mutex;
-use std::collections::HashMap;
+use rocket::tokio::task;
+use std::str::FromStr;
 use std::time::SystemTime;
+use tokei::LanguageType;
 
-#[derive(Serialize, Debug)]
+use crate::activity::Activity;
+use crate::authors::Authorship;
+use crate::config::{Config, StorageKind};
+use crate::error::KloccError;
+use crate::exclude::{self, ExcludedInfo};
+use crate::storage::{self, FileStorage, Kind, MemoryStorage, Storage};
+
+#[derive(Serialize, Deserialize, Default, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct Info {
     pub code: u32,
@@ -17,7 +27,7 @@ impl Info {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct FileInfo {
     pub name: String,
@@ -39,12 +49,16 @@ impl FileInfo {
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct LanguageInfo {
     pub name: String,
     pub total: Info,
     pub files: Vec<FileInfo>,
+    // Languages embedded into the files of this language (e.g. code blocks in markdown), which are not
+    // included in the 'total' above. Files of the children only count lines of the embedded code.
+    #[serde(default)]
+    pub children: Vec<LanguageInfo>,
 }
 
 impl LanguageInfo {
@@ -53,24 +67,46 @@ impl LanguageInfo {
             name,
             total,
             files: Vec::new(),
+            children: Vec::new(),
         }
     }
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 pub struct Data {
     pub creation_time: u64,
     pub verified_time: u64,
     pub repo: String,
     pub hash: String,
-    // pub branch: String,
+    pub branch: String,
+    // Whether the repository was analyzed using server-side credentials (see 'Credentials'), in which
+    // case the result is only visible to callers authorized to use those credentials.
+    #[serde(default)]
+    pub private: bool,
+    // Options used to produce this result. Older entries were produced with the defaults.
+    #[serde(default)]
+    pub options: CountOptions,
     pub total: Info,
+    // Total of the code embedded into other languages (see 'LanguageInfo::children'), which is included
+    // into the 'total' unless asked otherwise (see 'CountOptions::include_embedded').
+    #[serde(default)]
+    pub embedded: Info,
+    // Contributors of the code, only present when requested (see 'CountOptions::authors').
+    #[serde(default)]
+    pub authorship: Option<Authorship>,
+    // Size of the repository on disk (including git metadata), which determines how long the result stays
+    // fresh (see 'Freshness'). Older entries don't have it, and are treated as the small ones.
+    #[serde(default)]
+    pub size_bytes: u64,
+    // Files that were not counted, see 'CountOptions' and 'Exclusions'.
+    #[serde(default)]
+    pub excluded: ExcludedInfo,
     pub languages: Vec<LanguageInfo>,
 }
 
 impl Data {
-    pub fn new(repo: String, total: Info) -> Self {
+    pub fn new(repo: String, branch: String, total: Info) -> Self {
         // Note(andrew): Getting current timestamp in seconds from system clock here,
         //     which might be used later for checking whether our cached data is very
         //     recent or anything else. The type of 'SystemTime' duration is u64, so
@@ -86,18 +122,212 @@ impl Data {
             creation_time: now.as_secs(),
             verified_time: now.as_secs(),
             repo,
+            branch,
+            private: false,
+            options: CountOptions::default(),
             total,
+            embedded: Info::default(),
+            authorship: None,
+            size_bytes: 0,
+            excluded: ExcludedInfo::default(),
             languages: Vec::new(),
             hash: "".to_string(),
         }
     }
 }
 
-pub type Database = Mutex<HashMap<String, Data>>;
+// Options of the analysis, which callee can pass along with the job request. Every combination of
+// options produces different result, so it is a part of the cache key (see 'cache_key'), and is also
+// echoed back in the result, so it's clear how exactly the numbers were produced.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug)]
+#[serde(crate = "rocket::serde", default)]
+pub struct CountOptions {
+    // Gitignore-like patterns of files, that should not be counted (e.g. 'vendor/' or '*.min.js').
+    pub exclude: Vec<String>,
+    // Note(andrew): Options below are passed to tokei as is, refer to: https://docs.rs/tokei/latest/tokei/struct.Config.html
+    //     Ignore files are the ones committed to the repository, since the clone has nothing else.
+    pub hidden: bool,
+    pub no_ignore: bool,
+    pub no_ignore_parent: bool,
+    pub no_ignore_vcs: bool,
+    pub treat_doc_strings_as_comments: bool,
+    // Names of the languages to count (e.g. 'Rust'), where empty list means every language.
+    pub types: Vec<String>,
+    // Whether code embedded into other languages is included into the top-level total of the result.
+    pub include_embedded: bool,
+    // Whether code lines should be attributed to their authors (see 'Authorship'), which requires full
+    // clone of the repository, and is much slower than counting alone.
+    pub authors: bool,
+}
+
+impl Default for CountOptions {
+    fn default() -> Self {
+        Self {
+            exclude: Vec::new(),
+            hidden: false,
+            no_ignore: false,
+            no_ignore_parent: false,
+            no_ignore_vcs: false,
+            // Note(andrew): This is what we always did, before options were configurable.
+            treat_doc_strings_as_comments: true,
+            types: Vec::new(),
+            include_embedded: true,
+            authors: false,
+        }
+    }
+}
+
+impl CountOptions {
+    // Check that options are valid, and bring them into the canonical form, so the same options written
+    // differently (e.g. 'rust' and 'Rust') end up in the same cache entry.
+    pub fn validate(mut self) -> Result<Self, KloccError> {
+        exclude::validate(&self.exclude)?;
+
+        let mut types = Vec::new();
+        for name in self.types.iter() {
+            match LanguageType::from_str(name) {
+                Ok(value) => types.push(value),
+                Err(_) => return Err(KloccError::InvalidOptions(format!("Unknown language '{}'.", name))),
+            }
+        }
+        // Note(andrew): Order of the languages doesn't matter (unlike order of exclude patterns).
+        types.sort();
+        types.dedup();
+        self.types = types.iter().map(|t| t.to_string()).collect();
+
+        Ok(self)
+    }
+
+    // Languages to count, or 'None' for every language. Unknown names are skipped, since options are
+    // expected to be validated already.
+    pub fn language_types(&self) -> Option<Vec<LanguageType>> {
+        if self.types.is_empty() {
+            return None; // Early return.
+        }
+        Some(
+            self.types
+                .iter()
+                .filter_map(|name| LanguageType::from_str(name).ok())
+                .collect(),
+        )
+    }
+}
+
+// Cache key of the analysis result for the given repository and target ref. Each ref (branch, tag or
+// commit) is stored separately, so analyzing one branch doesn't overwrite results of another. Results
+// produced with server-side credentials are also stored separately per credential, so they can never
+// be served in response to the anonymous request for the same repository.
+pub fn cache_key(repo_url: &str, target: &str, credential: Option<&str>, options: &CountOptions) -> String {
+    let mut key = match credential {
+        Some(name) => format!("{}#{}@{}", repo_url, target, name),
+        None => format!("{}#{}", repo_url, target),
+    };
+
+    // Note(andrew): Default options are left out of the key, so the most common requests keep the
+    //     same key as before options were introduced (and existing cache entries stay valid). Order
+    //     of the patterns is kept as is, since later patterns can override earlier ones.
+    if *options != CountOptions::default() {
+        // @SafeUnwrap: Options are plain values, which always serialize into json.
+        key.push('?');
+        key.push_str(&to_string(options).unwrap());
+    }
+
+    key
+}
+
+pub type Database = Mutex<Box<dyn Storage>>;
 
-// A helper function to create an empty instance of the hashmap-mutex structure, which
-// is used as in-memory storage.
-pub fn init_db() -> Database {
-    let storage = HashMap::<String, Data>::new();
+// A helper function to create an instance of the storage-mutex structure, which is used as a cache
+// storage, either in-memory or on disk, depending on the configuration.
+pub fn init_db(config: &Config) -> Database {
+    let budget = config.cache_budget_mb.saturating_mul(1024 * 1024);
+    let storage: Box<dyn Storage> = match config.storage {
+        StorageKind::Memory => Box::new(MemoryStorage::new(budget)),
+        // Note(andrew): Failing to open configured storage is fatal, since silently falling back to the
+        //     memory would throw away the cache on next restart, which is exactly what operator tried to avoid.
+        StorageKind::File => match FileStorage::open(&config.storage_dir, budget) {
+            Ok(storage) => Box::new(storage),
+            Err(e) => panic!("{}", e),
+        },
+    };
     Mutex::new(storage)
 }
+
+// Note(andrew): Entries evicted from memory (see 'FileStorage') are read back from the disk before the storage
+//     is locked for the actual use, so the whole server is not waiting for the disk while we are holding the
+//     lock. This has to be called right before the use, but entry might still be evicted again in between
+//     (by the insert of someone else), in which case it is simply missing, the same way as any other miss.
+pub async fn preload(db: &Database, kind: Kind, keys: &[&str]) {
+    for key in keys {
+        let evicted = match db.lock().await.evicted(kind, key) {
+            Some(value) => value,
+            None => continue, // Already in memory (or nowhere to read it from).
+        };
+        if let Ok(Some(entry)) = task::spawn_blocking(move || evicted.read()).await {
+            db.lock().await.restore(entry);
+        }
+    }
+}
+
+// Same as 'preload', but for the code which is already running in the blocking thread pool.
+pub fn preload_blocking(db: &Database, kind: Kind, keys: &[&str]) {
+    for key in keys {
+        let evicted = db.blocking_lock().evicted(kind, key);
+        if let Some(entry) = evicted.and_then(|evicted| evicted.read()) {
+            db.blocking_lock().restore(entry);
+        }
+    }
+}
+
+// Note(andrew): Same as with the reads (see 'preload'), new entries are serialized (and written to the disk,
+//     see 'FileStorage') before the storage is locked, and the lock is only held to keep them in memory. The
+//     entry is still in memory if writing it to the disk fails, so the errors are only logged (see 'Disk').
+pub async fn store(db: &Database, key: String, data: Data) {
+    let disk = db.lock().await.disk();
+    let result = task::spawn_blocking(move || {
+        let size = storage::prepare(disk.as_ref(), Kind::Data, &key, &data);
+        (key, data, size)
+    })
+    .await;
+
+    match result {
+        Ok((key, data, size)) => db.lock().await.insert(key, data, size),
+        Err(e) => info!("Failed to store the result: {}", e),
+    }
+}
+
+// Same as 'store', but for the activity reports.
+pub async fn store_activity(db: &Database, key: String, activity: Activity) {
+    let disk = db.lock().await.disk();
+    let result = task::spawn_blocking(move || {
+        let size = storage::prepare(disk.as_ref(), Kind::Activity, &key, &activity);
+        (key, activity, size)
+    })
+    .await;
+
+    match result {
+        Ok((key, activity, size)) => db.lock().await.insert_activity(key, activity, size),
+        Err(e) => info!("Failed to store the activity report: {}", e),
+    }
+}
+
+// Same as 'store', but for the code which is already running in the blocking thread pool.
+pub fn store_blocking(db: &Database, key: String, data: Data) {
+    let disk = db.blocking_lock().disk();
+    let size = storage::prepare(disk.as_ref(), Kind::Data, &key, &data);
+    db.blocking_lock().insert(key, data, size);
+}
+
+// Update time of the last verification (see 'Freshness') of the existing entry, see 'store'.
+pub async fn verify(db: &Database, key: &str, time: u64) {
+    let disk = {
+        let mut guard = db.lock().await;
+        guard.set_verified_time(key, time);
+        guard.disk()
+    };
+
+    if let Some(disk) = disk {
+        let key = key.to_string();
+        let _ = task::spawn_blocking(move || disk.write_verified(&key, time)).await;
+    }
+}
@@ -0,0 +1,208 @@
+use rocket::serde::Serialize;
+use rocket::serde::json::{Value, json};
+use std::collections::BTreeMap;
+
+use crate::data::{Data, FileInfo, Info};
+
+// One side of the comparison: the ref, as requested, and the commit it was resolved to.
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Side {
+    #[serde(rename = "ref")]
+    pub target: String,
+    pub hash: String,
+    // Key of the analysis result of this commit in the cache.
+    #[serde(skip)]
+    pub key: String,
+}
+
+// Entry (e.g. language or file) on the base and on the head side, where missing one is not present there.
+type Pair<'a, T> = (Option<&'a T>, Option<&'a T>);
+
+// Difference in the amount of lines, which is negative when lines were removed.
+#[derive(Serialize, Default, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde")]
+struct Delta {
+    code: i64,
+    comments: i64,
+    blanks: i64,
+}
+
+impl Delta {
+    fn new(base: Option<(u32, u32, u32)>, head: Option<(u32, u32, u32)>) -> Self {
+        let (base_code, base_comments, base_blanks) = base.unwrap_or_default();
+        let (head_code, head_comments, head_blanks) = head.unwrap_or_default();
+        Self {
+            code: (head_code as i64) - (base_code as i64),
+            comments: (head_comments as i64) - (base_comments as i64),
+            blanks: (head_blanks as i64) - (base_blanks as i64),
+        }
+    }
+
+    fn lines(&self) -> i64 {
+        self.code.abs() + self.comments.abs() + self.blanks.abs()
+    }
+}
+
+fn info_lines(info: &Info) -> (u32, u32, u32) {
+    (info.code, info.comments, info.blanks)
+}
+
+fn file_lines(file: &FileInfo) -> (u32, u32, u32) {
+    (file.code, file.comments, file.blanks)
+}
+
+// Note(andrew): Status of the language or the file, where 'modified' only means that the amount of lines has
+//     changed (changes, which keep the amount of lines the same, are invisible to us). Entries which have not
+//     changed are left out of the diff entirely.
+fn status<T>(base: &Option<T>, head: &Option<T>) -> &'static str {
+    match (base, head) {
+        (None, Some(_)) => "added",
+        (Some(_), None) => "removed",
+        _ => "modified",
+    }
+}
+
+// Build the difference between analysis results of two commits of the same repository, per language and per
+// file, where the biggest changes go first.
+pub fn compare(base: &Side, base_data: &Data, head: &Side, head_data: &Data) -> Value {
+    let mut languages: BTreeMap<&str, Pair<Info>> = BTreeMap::new();
+    let mut files: BTreeMap<(&str, &str), Pair<FileInfo>> = BTreeMap::new();
+
+    for lang in base_data.languages.iter() {
+        languages.entry(&lang.name).or_default().0 = Some(&lang.total);
+        for file in lang.files.iter() {
+            files.entry((&lang.name, &file.path)).or_default().0 = Some(file);
+        }
+    }
+    for lang in head_data.languages.iter() {
+        languages.entry(&lang.name).or_default().1 = Some(&lang.total);
+        for file in lang.files.iter() {
+            files.entry((&lang.name, &file.path)).or_default().1 = Some(file);
+        }
+    }
+
+    let mut language_deltas: Vec<(Delta, Value)> = languages
+        .into_iter()
+        .map(|(name, (b, h))| {
+            let delta = Delta::new(b.map(info_lines), h.map(info_lines));
+            let changed = delta != Delta::default() || b.is_none() || h.is_none();
+            (name, delta, status(&b, &h), changed)
+        })
+        .filter(|(_, _, _, changed)| *changed)
+        .map(|(name, delta, status, _)| (delta, json!({"name": name, "status": status, "delta": delta})))
+        .collect();
+    language_deltas.sort_by_key(|(delta, _)| -delta.lines());
+
+    let mut file_deltas: Vec<(Delta, Value)> = files
+        .into_iter()
+        .map(|((language, path), (b, h))| {
+            let delta = Delta::new(b.map(file_lines), h.map(file_lines));
+            let changed = delta != Delta::default() || b.is_none() || h.is_none();
+            (language, path, delta, status(&b, &h), changed)
+        })
+        .filter(|(_, _, _, _, changed)| *changed)
+        .map(|(language, path, delta, status, _)| {
+            (
+                delta,
+                json!({"path": path, "language": language, "status": status, "delta": delta}),
+            )
+        })
+        .collect();
+    file_deltas.sort_by_key(|(delta, _)| -delta.lines());
+
+    json!({
+        "base": base, "head": head,
+        "total": Delta::new(Some(info_lines(&base_data.total)), Some(info_lines(&head_data.total))),
+        "languages": language_deltas.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+        "files": file_deltas.into_iter().map(|(_, v)| v).collect::<Vec<_>>(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::LanguageInfo;
+
+    type Lines = (u32, u32, u32);
+
+    fn side(target: &str) -> Side {
+        Side {
+            target: target.to_string(),
+            hash: target.to_string(),
+            key: target.to_string(),
+        }
+    }
+
+    fn data(languages: &[(&str, &[(&str, Lines)])]) -> Data {
+        let sum = |files: &[(&str, Lines)]| {
+            files
+                .iter()
+                .fold(Info::default(), |total, (_, (code, comments, blanks))| {
+                    Info::new(total.code + code, total.comments + comments, total.blanks + blanks)
+                })
+        };
+
+        let mut data = Data::new("repo".to_string(), "HEAD".to_string(), Info::default());
+        for (name, files) in languages {
+            let total = sum(files);
+            data.total = Info::new(
+                data.total.code + total.code,
+                data.total.comments + total.comments,
+                data.total.blanks + total.blanks,
+            );
+
+            let mut language = LanguageInfo::new(name.to_string(), total);
+            for (path, (code, comments, blanks)) in files.iter() {
+                let file = FileInfo::new(path.to_string(), path.to_string(), *code, *comments, *blanks);
+                language.files.push(file);
+            }
+            data.languages.push(language);
+        }
+        data
+    }
+
+    #[test]
+    fn compare_reports_added_and_removed_files() {
+        let base = data(&[
+            ("Python", &[("old.py", (5, 0, 0))]),
+            ("Rust", &[("a.rs", (6, 1, 1)), ("b.rs", (4, 1, 0))]),
+        ]);
+        let head = data(&[
+            ("Rust", &[("a.rs", (6, 1, 1)), ("c.rs", (6, 1, 0))]),
+            ("TOML", &[("Cargo.toml", (3, 0, 0))]),
+        ]);
+        let diff = compare(&side("main"), &base, &side("feature"), &head);
+
+        assert_eq!(diff["total"], json!({"code": 0, "comments": 0, "blanks": 0}));
+        assert_eq!(
+            diff["languages"],
+            json!([
+                {"name": "Python", "status": "removed", "delta": {"code": -5, "comments": 0, "blanks": 0}},
+                {"name": "TOML", "status": "added", "delta": {"code": 3, "comments": 0, "blanks": 0}},
+                {"name": "Rust", "status": "modified", "delta": {"code": 2, "comments": 0, "blanks": 0}},
+            ])
+        );
+
+        // Unchanged 'a.rs' is left out, and the biggest changes go first.
+        let files: Vec<(&str, &str)> = diff["files"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| (file["path"].as_str().unwrap(), file["status"].as_str().unwrap()))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("c.rs", "added"),
+                ("old.py", "removed"),
+                ("b.rs", "removed"),
+                ("Cargo.toml", "added")
+            ]
+        );
+        assert_eq!(
+            diff["files"][2]["delta"],
+            json!({"code": -4, "comments": -1, "blanks": 0})
+        );
+    }
+}
@@ -1,33 +1,45 @@
 use prometheus::{self, Encoder, TextEncoder};
-use rocket::State;
+use rocket::response::stream::{Event, EventStream};
 use rocket::serde::json::{Value, json};
-use rocket::tokio::task;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::error::RecvError;
+use rocket::{Shutdown, State};
+use std::sync::Arc;
 use std::time::SystemTime;
 
-use crate::body::PostJobData;
-use crate::counter::{get_data_from_repo, get_latest_hash};
-use crate::data::Database;
-use crate::prom::TOTAL_REPOSITORIES_SERVED;
-use crate::utils::expand_url;
+use crate::badge::{self, Badge, BadgeQuery};
+use crate::body::{PostActivityData, PostDiffData, PostJobData};
+use crate::counter::validate_ref;
+use crate::credentials::{CallerToken, Credentials};
+use crate::data::{self, CountOptions, Database, cache_key};
+use crate::diff;
+use crate::error::KloccError;
+use crate::formats::{self, Format};
+use crate::jobs::{Job, JobEventKind, JobMode, JobOutput, JobQueue, JobRequest, JobStatus};
+use crate::providers::Providers;
+use crate::query::{self, RepoQuery};
+use crate::response::{ApiResponse, BadgeResponse, FormattedResponse};
+use crate::storage::Kind;
+use crate::timeline;
+use crate::tree;
 
 // Note(andrew): To avoid spamming git server with a check for latest commit hash
 //     on every request, which is extremely slow and not productive (sending 1000
 //     requests to our API will make us bombard targeted git server with requests
 //     too), we can check 'verified_time' variable for each repository in our cache
-//     and if since the last check the amount of time passed is less than value of
-//     'VERIFY_MIN_INTERVAL', we assume data as valid. This value needs to be kept
-//     low enough so it is very unlikely for someone who does not try to spam our
-//     API to be confused or frustrated with outdated data. Anyway, this check is
-//     mostly targeted to improve APIs performance, and not really to prevent all
-//     kinds of potential DoS attacks (i.e. it is much easier to just spam the API
-//     with huge amount of requests with new repository target in each of them).  @Robustness @Incomplete
-const VERIFY_MIN_INTERVAL: u64 = 60 * 5; // @Robustness: Hopefully verifying cache integrity once in 5 minutes will not cause any problems in our case.
+//     and if since the last check the amount of time passed is less than the
+//     'verify_interval' for the size of repository (see 'Freshness'), we assume
+//     data as valid. This value needs to be kept low enough so it is very unlikely
+//     for someone who does not try to spam our API to be confused or frustrated
+//     with outdated data. Anyway, this check is mostly targeted to improve APIs
+//     performance, and not really to prevent all kinds of potential DoS attacks
+//     (i.e. it is much easier to just spam the API with huge amount of requests
+//     with new repository target in each of them).  @Robustness @Incomplete
 
 /*
-   First, we are trying to pull data from the cache (in-memory database for now),
-   for now this will be a single unique entry per repository (only github), since
-   we are not allowing to pass any target branch -- only the default branch -- for
-   analysis.
+   First, we are trying to pull data from the cache (see 'Storage' implementations),
+   which has a single unique entry per repository and target ref (branch, tag or
+   commit, where the default branch is used when ref is not specified).
 
    If we find anything in the cache, we are happy to instantly respond with existing
    data.
@@ -42,7 +54,7 @@ const VERIFY_MIN_INTERVAL: u64 = 60 * 5; // @Robustness: Hopefully verifying cac
          repository some time ago, and it's still in-memory, we yield invalid results,
          even though they are probably pretty close, at first (still pretty bad).
 
-     [ ] Current implementation is just holding request open until we are done. This is
+     [x] Current implementation is just holding request open until we are done. This is
          arguably a flawed solution. Below I describe alternative implementation ideas,
          all of which would be important to at least carefully consider:
 
@@ -63,10 +75,20 @@ const VERIFY_MIN_INTERVAL: u64 = 60 * 5; // @Robustness: Hopefully verifying cac
          Note(andrew): If this is actually something we are going to implement (anything of
              the above), there might be a growing need for persistent storage (see below).
 
-     [?] We are not allowing to choose target branch, so the database, aka caching,
+         Note(andrew): We went with the job queue (see 'JobQueue'). POST /api/jobs dispatches
+             the job to the background workers and returns job id, and the status (with data,
+             once it's done) is available at GET /api/jobs/<id>. For frontends that don't want
+             to poll, there is also a Server-Sent Events stream at GET /api/jobs/<id>/events,
+             which is one-way version of the websockets idea, and works over plain HTTP.
+
+     [x] We are not allowing to choose target branch, so the database, aka caching,
          needs to work properly with branches later.
 
-     [?] No persistent storage is being used. Note(andrew): I don't think we need any.
+     [x] No persistent storage is being used. Note(andrew): I don't think we need any.
+
+         Note(andrew): Turns out we do, since every deploy was throwing away hours of analysis of big
+             repositories. Storage is now configurable (see 'Storage'), and 'file' storage survives
+             restarts.
 */
 
 // This endpoint is designed to help monitor and debug service availability. It
@@ -75,15 +97,21 @@ const VERIFY_MIN_INTERVAL: u64 = 60 * 5; // @Robustness: Hopefully verifying cac
 // don't have 'format' (content-type header) required for this endpoint (or any
 // other header requirements), so *any* GET request has to be valid here.
 #[get("/health")]
-pub async fn get_health(db: &State<Database>) -> Value {
-    // Just for informational purposes add count of total cached items
-    // in the storage to the response (TODO(andrew): add storage size,
-    // meaning an actual amount of memory taken by cache).
-    let count = db.lock().await.len();
+pub async fn get_health(db: &State<Arc<Database>>) -> ApiResponse {
+    // Just for informational purposes add count of cached line counts
+    // kept in memory to the response, and the (approximate) amount of
+    // memory taken by the cache (see 'Lru'). Entries evicted to the
+    // disk (see 'FileStorage') are not counted, since listing them
+    // would mean reading the storage directory on every healthcheck.
+    let (count, size_bytes) = {
+        let guard = db.lock().await;
+        (guard.len(), guard.size_bytes())
+    };
     json!({
         "status": 200, "message_code": "info_health_ok", "message": "KLOCC is healthy!",
-        "data": {"cached_count": count},
+        "data": {"cached_count": count, "cached_size_bytes": size_bytes},
     })
+    .into()
 }
 
 // Native metrics export support for Prometheus.
@@ -97,143 +125,545 @@ pub async fn get_metrics(encoder: &State<TextEncoder>) -> String {
 }
 
 #[post("/jobs", format = "application/json", data = "<data>")]
-pub async fn post_klocc_job(db: &State<Database>, data: PostJobData) -> Value {
+pub async fn post_klocc_job(
+    db: &State<Arc<Database>>,
+    queue: &State<JobQueue>,
+    providers: &State<Providers>,
+    credentials: &State<Credentials>,
+    caller: CallerToken,
+    data: PostJobData,
+) -> ApiResponse {
     // Note(andrew): First thing first, we are trying to expand service name into url, using our
     //     helper function. If it fails to match provider to any known service, it returns an error
     //     message, explaining the problem, which we pass through json directly to the callee. To
-    //     see/update the list of supported providers for check the source of 'expand_url', that's
-    //     the only place where we pattern match it.
-    let repo_url = match expand_url(&data.provider, &data.username, &data.reponame) {
+    //     see/update the list of supported providers check 'Providers', which are built-in providers
+    //     extended by the ones configured by operator.
+    let repo_url = match providers.expand_url(&data.provider, &data.username, &data.reponame) {
         Ok(value) => value,
-        Err(msg) => {
-            return json!({ "status": 400, "message_code": "err_bad_service", "message": msg });
-        } // Early return from the handler.
-    };
-
-    // TODO(andrew): Since we are getting 'data' here, store it outside the code block, because
-    //     we want to query it again later. Or should we still read it from mutex (sounds like
-    //     some potential race conditions regarding parallel-processed requests are possible, or
-    //     even 'to be expected', so better to think carefully about this)?  @Robustness @Speed
-
-    // Note(andrew): Before requesting latest hash from the remote, let's check if the data is
-    //     present in the cache, and if it is, we can check if it is recent enough (integrity
-    //     was verified with latest hash less than 'VERIFY_MIN_INTERVAL' seconds ago). And if
-    //     it is recent enough, we can immediately return, as we are not concerned enough about
-    //     validity of the that data to take time for additional metadata request. Hopefully,
-    //     this will increase our robustness and allow us to survive situations like 'DoS attack'
-    //     (either intentional or just an unexpected amount of continuous load, hammering small
-    //     range of cached repositories).
-    {
-        let guard = db.lock().await; // It is important for us that this lock will be freed after the code block.
-
-        // @SafeUnwrap: Data has to be present to continue, so we use safe unwrap condition.
-        if let Some(data) = guard.get(&repo_url) {
-            let curr = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap(); // Get current system time. @UnsafeUnwrap
-
-            if (data.verified_time + VERIFY_MIN_INTERVAL) >= curr.as_secs() {
-                return json!({
-                    "status": 200, "message_code": "info_success_cached_recent",
-                    "message": "Your request was satisfied instantly, because it was found in cache.",
-                    "data": data,
-                }); // Early return from the handler.
-            }
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+
+    // Note(andrew): Options are checked here, so the callee learns about a typo right away, instead of
+    //     waiting for the job to fail after the whole clone.
+    let options = match data.options.validate() {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    let timeline = match data.timeline.map(|t| t.validate()).transpose() {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+
+    // Note(andrew): When no ref is specified, we are analyzing the default branch, which is what the
+    //     remote 'HEAD' points to.
+    let target = data.target.unwrap_or_else(|| "HEAD".to_string());
+    if let Err(e) = validate_ref(&target) {
+        return error_response(&e).into(); // Early return from the handler.
+    }
+
+    // Note(andrew): Server-side credentials are only used if caller is allowed to use them, otherwise
+    //     the repository is accessed anonymously (and private one will fail to be fetched). This also
+    //     determines the cache entry, since results produced with credentials are stored separately.
+    let credential = credentials.authorize(&data.provider, &data.username, &caller);
+    let request = JobRequest {
+        env: credential
+            .as_deref()
+            .map(|name| credentials.git_env(name, &repo_url))
+            .unwrap_or_default(),
+        username: data.username,
+        reponame: data.reponame,
+        repo_url,
+        target,
+        credential,
+        options,
+        mode: match timeline {
+            Some(timeline) => JobMode::Timeline(timeline),
+            None => JobMode::Count,
+        },
+    };
+    let key = request.key();
+
+    // Note(andrew): Before queueing the job, let's check if the data is present in the cache, and if
+    //     it is, we can check if it is recent enough (integrity was verified with latest hash less than
+    //     'verify_interval' seconds ago, see 'Freshness'). And if it is recent enough, we can immediately
+    //     return, as we are not concerned enough about validity of the that data to take time for
+    //     additional metadata request. Hopefully, this will increase our robustness and allow us to
+    //     survive situations like 'DoS attack' (either intentional or just an unexpected amount of
+    //     continuous load, hammering small range of cached repositories).
+    //
+    //     Timelines are never served from here, since they consist of many results, and we don't know which
+    //     ones without looking at the history first (which is done by the worker).
+    let recent = matches!(request.mode, JobMode::Count) && {
+        data::preload(db, Kind::Data, &[&key]).await;
+        let mut guard = db.lock().await; // It is important for us that this lock will be freed after the code block.
+        let curr = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap(); // Get current system time. @UnsafeUnwrap
+
+        match guard.get(&key) {
+            Some(data) => (data.verified_time + queue.freshness(data.size_bytes).verify_interval) >= curr.as_secs(),
+            None => false,
         }
+    };
+
+    if recent {
+        let code = "info_success_cached_recent";
+        let msg = "Your request was satisfied instantly, because it was found in cache.";
+        let job = queue.finished(&request, code, msg).await;
+        return job_response(db, job).await.into(); // Early return from the handler.
     }
 
-    let hash: String;
-    {
-        // Preparing values to move into the thread.
-        let _repo_url = repo_url.clone();
-        let _target = "HEAD".to_string();
-        // This method will return a hash of the latest commit in the repository for us to save for later,
-        // or an error if the repository doesn't exist (or it's not available).
-        hash = match task::spawn_blocking(move || get_latest_hash(_repo_url, _target))
-            .await
-            .unwrap()
-        {
-            // @UnsafeUnwrap @Robustness: Thread can fail?
-            Ok(value) => value,
-            Err(msg) => {
-                return json!({"status": 400, "message_code": "err_failed_to_fetch_from_repo", "message": msg});
-            } // Early return from the handler.
-        };
+    // Note(andrew): Otherwise, the job is dispatched to the background workers (see 'JobQueue'), which
+    //     will verify cached data against the latest commit hash, or analyze the repository from scratch.
+    //     We don't wait for any of that, and respond with the job id immediately, so the callee can poll
+    //     job status at 'GET /api/jobs/<id>'.
+    match queue.submit(request).await {
+        Ok(job) => job_response(db, job).await.into(),
+        Err(e) => error_response(&e).into(),
     }
+}
 
-    {
-        // Note(andrew): Here we are asynchronously waiting on the lock, if the resource is busy,
-        //     and otherwise we just grab the guard and lock data storage. Then we are looking for
-        //     our repository in the cache, and if it is present (the result of .get is not empty),
-        //     we are passing grabbed data to write it back into the response to the callee, if it
-        //     is relevant. Relevancy is determined by checking if the latest hash at the HEAD of
-        //     the repository macthes stored hash. If it does not match, we know that new commits
-        //     have been pushed to the repository since our last analysis, so there is some chance
-        //     (although we don't know, and probably don't have a way to know, exactly) that stored
-        //     result is inaccurate.
-        let mut guard = db.lock().await; // It is important for us that this lock will be freed after the code block.
+// Note(andrew): Comparison of two refs of the repository (e.g. target branch of the PR and the PR itself),
+//     which is a job as well, since both refs might need to be analyzed first. Result of the job is the
+//     delta of lines from 'base' to 'head', per language and per file, once it's done. Analysis results
+//     are cached per commit, so comparing against the same base again is mostly free.
+#[post("/diffs", format = "application/json", data = "<data>")]
+pub async fn post_klocc_diff(
+    db: &State<Arc<Database>>,
+    queue: &State<JobQueue>,
+    providers: &State<Providers>,
+    credentials: &State<Credentials>,
+    caller: CallerToken,
+    data: PostDiffData,
+) -> ApiResponse {
+    let repo_url = match providers.expand_url(&data.provider, &data.username, &data.reponame) {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    let options = match data.options.validate() {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    if let Err(e) = validate_ref(&data.base).and_then(|_| validate_ref(&data.head)) {
+        return error_response(&e).into(); // Early return from the handler.
+    }
 
-        // @SafeUnwrap: Data has to be present to continue, so we use safe unwrap condition.
-        if let Some(data) = guard.get_mut(&repo_url) {
-            // Verify that hash matches since the last time we ran the klocc job.
-            if data.hash == hash {
-                // Note(andrew): Before returning, we need to get current system time and update cached
-                //     data field with it, so we will be able to tell on the next request with the same
-                //     cached target whether we updated it recently enough and can respond immediately.
-                let curr = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap(); // Get current system time. @UnsafeUnwrap
-                data.verified_time = curr.as_secs();
-
-                return json!({
-                    "status": 200, "message_code": "info_success_cached",
-                    "message": "Your request was satisfied instantly, because it was found in cache.",
-                    "data": data,
-                }); // Early return from the handler.
+    // Same as for the jobs, see 'post_klocc_job'.
+    let credential = credentials.authorize(&data.provider, &data.username, &caller);
+    let request = JobRequest {
+        env: credential
+            .as_deref()
+            .map(|name| credentials.git_env(name, &repo_url))
+            .unwrap_or_default(),
+        username: data.username,
+        reponame: data.reponame,
+        repo_url,
+        target: data.head,
+        credential,
+        options,
+        mode: JobMode::Diff(data.base),
+    };
+
+    match queue.submit(request).await {
+        Ok(job) => job_response(db, job).await.into(),
+        Err(e) => error_response(&e).into(),
+    }
+}
+
+// Note(andrew): Commit activity of the repository (see 'Activity'), which is a separate kind of analysis,
+//     made from the whole history of the ref instead of its latest commit. Otherwise it works exactly like
+//     the line counting job, including the cache and its freshness (see 'post_klocc_job').
+#[post("/activity", format = "application/json", data = "<data>")]
+pub async fn post_klocc_activity(
+    db: &State<Arc<Database>>,
+    queue: &State<JobQueue>,
+    providers: &State<Providers>,
+    credentials: &State<Credentials>,
+    caller: CallerToken,
+    data: PostActivityData,
+) -> ApiResponse {
+    let repo_url = match providers.expand_url(&data.provider, &data.username, &data.reponame) {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    let target = data.target.unwrap_or_else(|| "HEAD".to_string());
+    if let Err(e) = validate_ref(&target) {
+        return error_response(&e).into(); // Early return from the handler.
+    }
+
+    let credential = credentials.authorize(&data.provider, &data.username, &caller);
+    let request = JobRequest {
+        env: credential
+            .as_deref()
+            .map(|name| credentials.git_env(name, &repo_url))
+            .unwrap_or_default(),
+        username: data.username,
+        reponame: data.reponame,
+        repo_url,
+        target,
+        credential,
+        options: CountOptions::default(),
+        mode: JobMode::Activity,
+    };
+    let key = request.key();
+
+    let recent = {
+        data::preload(db, Kind::Activity, &[&key]).await;
+        let mut guard = db.lock().await;
+        let curr = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap(); // @UnsafeUnwrap
+
+        match guard.get_activity(&key) {
+            Some(activity) => {
+                (activity.verified_time + queue.freshness(activity.size_bytes).verify_interval) >= curr.as_secs()
             }
+            None => false,
         }
+    };
+
+    if recent {
+        let code = "info_success_cached_recent";
+        let msg = "Your request was satisfied instantly, because it was found in cache.";
+        let job = queue.finished(&request, code, msg).await;
+        return job_response(db, job).await.into(); // Early return from the handler.
     }
 
-    {
-        // Note(andrew): Here we are copying values from our input strings, because we are going to
-        // pass them down into the thread, which will own them from now on (but we might want to use
-        // original values later in the code).
-        let _username = data.username.clone();
-        let _reponame = data.reponame.clone();
-        let _repo_url = repo_url.clone();
-
-        // Note(andrew): Here we are using high-level tokio API for dispatching synchronous tasks in
-        //     asynchronous manner, by 'moving' them into a newly spawned thread and awaiting until it
-        //     finishes (wait is asynchronous). Which, in practice, means that the server can process
-        //     other requests in the meantime and do other useful work, while we are waiting for download
-        //     or result of analysis (everything inside dispatched routine below).
-        let result = task::spawn_blocking(move || get_data_from_repo(_username, _reponame, _repo_url))
+    match queue.submit(request).await {
+        Ok(job) => job_response(db, job).await.into(),
+        Err(e) => error_response(&e).into(),
+    }
+}
+
+// Note(andrew): Passing 'tree' (e.g. '?tree=2') replaces the result of the line counting job with its
+//     directory tree, down to the given depth (see 'tree::build'), so the callee doesn't have to rebuild
+//     it from the flat list of files. Other kinds of jobs ignore it.
+#[get("/jobs/<id>?<tree>")]
+pub async fn get_klocc_job(
+    db: &State<Arc<Database>>,
+    queue: &State<JobQueue>,
+    credentials: &State<Credentials>,
+    caller: CallerToken,
+    id: u64,
+    tree: Option<u32>,
+    format: Result<Format, KloccError>,
+) -> FormattedResponse {
+    let format = match format {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    let job = match queue.get(id).await {
+        Some(job) if can_see(credentials, &caller, &job) => job,
+        _ => return job_not_found(id).into(), // Early return from the handler.
+    };
+
+    // Note(andrew): Only the finished line counting job has the result to render in other formats (see
+    //     'Format'), everything else goes into the envelope, no matter what was asked for.
+    let formatted = job.status == JobStatus::Done && matches!(job.output, JobOutput::Data);
+    if formatted {
+        data::preload(db, Kind::Data, &[&job.key]).await;
+    }
+    if formatted
+        && let Some(body) = db
+            .lock()
             .await
-            .unwrap(); // @PotentialPanic @Robustness: Thread can fail?
-
-        // Note(andrew): Our klocc procedure returns a result, where different errors and edge-cases are
-        //     handled, explained and propagated in a form of an error message (as a string), so here we
-        //     are doing a check for that in our result. If we confirmed that this is indeed an error,
-        //     unpack the error message and pass it directly back to the callee.
-        if let Err(message) = result {
-            return json!({ "status": 500, "message_code": "err_counter_failed", "message": message });
-            // Early return from the handler.
+            .get(&job.key)
+            .and_then(|data| formats::render(data, format))
+    {
+        return FormattedResponse::Formatted(format.content_type(), body); // Early return from the handler.
+    }
+
+    job_response_with(db, job, tree).await.into()
+}
+
+// Note(andrew): Read-only view of the cached analysis result (with default options), which never starts
+//     the analysis itself, so the callee has to queue the job first (see 'post_klocc_job'). Results of big
+//     repositories are huge, hence filtering, selection of the fields and pagination over files (see
+//     'query::render'), which are all done on the cached 'Data' as is.
+#[get("/repos/<provider>/<username>/<reponame>?<query..>")]
+#[allow(clippy::too_many_arguments)] // Rocket hands us everything as arguments of the handler.
+pub async fn get_klocc_repo(
+    db: &State<Arc<Database>>,
+    providers: &State<Providers>,
+    credentials: &State<Credentials>,
+    caller: CallerToken,
+    provider: &str,
+    username: &str,
+    reponame: &str,
+    query: RepoQuery,
+    format: Result<Format, KloccError>,
+) -> FormattedResponse {
+    let format = match format {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    let repo_url = match providers.expand_url(provider, username, reponame) {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+    let view = match query.validate() {
+        Ok(value) => value,
+        Err(e) => return error_response(&e).into(), // Early return from the handler.
+    };
+
+    // Same entry as the one produced by the job with default options, see 'post_klocc_job'.
+    let target = query.target.as_deref().unwrap_or("HEAD");
+    if let Err(e) = validate_ref(target) {
+        return error_response(&e).into(); // Early return from the handler.
+    }
+    let credential = credentials.authorize(provider, username, &caller);
+    let key = cache_key(&repo_url, target, credential.as_deref(), &CountOptions::default());
+
+    data::preload(db, Kind::Data, &[&key]).await;
+    let mut guard = db.lock().await;
+    let data = match guard.get(&key) {
+        Some(value) => value,
+        None => {
+            let msg = format!(
+                "Repository '{}' at '{}' was not analyzed yet, queue the job first.",
+                repo_url, target
+            );
+            let body = json!({"status": 404, "message_code": "err_result_not_found", "message": msg});
+            return body.into(); // Early return from the handler.
         }
+    };
 
-        let mut data = result.unwrap(); // @SafeUnwrap: Data must be present, because we just checked for the error.
-        data.hash = hash;
-        // Note(andrew): Await and lock temporary mutex guard value, that is being used immediately in-place
-        //     to insert values into the cache, and then, in the next step after insert, the guard is freed
-        //     and the cache storage is unlocked for other parallel running jobs.
-        db.lock().await.insert(repo_url.clone(), data); // We are safe to unwrap here, because we check that value is ok right above.
+    // Other formats are always the whole result, since filters and pagination are ours (see 'Format').
+    if let Some(body) = formats::render(data, format) {
+        return FormattedResponse::Formatted(format.content_type(), body); // Early return from the handler.
+    }
 
-        // Tracking repository statistics.
-        TOTAL_REPOSITORIES_SERVED.inc();
+    match query::render(data, &view) {
+        Ok(result) => json!({
+            "status": 200, "message_code": "info_success_cached",
+            "message": "Your request was satisfied instantly, because it was found in cache.",
+            "data": result,
+        })
+        .into(),
+        Err(e) => error_response(&e).into(),
+    }
+}
+
+// Note(andrew): SVG badge for the README of the repository, served from the cache (with default options).
+//     Badges are public by nature, so the repository is always accessed anonymously, and the cache miss
+//     doesn't make the callee wait, but starts the analysis in the background and shows the 'analyzing'
+//     badge in the meantime. Outdated entries are served as is, while being verified in the background
+//     (same as with 'verify_interval' for the jobs, see 'Freshness'), and only fresh ones are allowed to be cached.
+#[get("/badge/<provider>/<username>/<reponame>?<query..>")]
+pub async fn get_klocc_badge(
+    db: &State<Arc<Database>>,
+    queue: &State<JobQueue>,
+    providers: &State<Providers>,
+    provider: &str,
+    username: &str,
+    reponame: &str,
+    query: BadgeQuery,
+) -> BadgeResponse {
+    let uncached = |badge: Badge| BadgeResponse {
+        svg: badge.render(),
+        max_age: None,
+    };
+
+    // Url of the badge ends with the extension, so it looks like an image to markdown renderers.
+    let reponame = reponame.strip_suffix(".svg").unwrap_or(reponame);
+    let repo_url = match providers.expand_url(provider, username, reponame) {
+        Ok(value) => value,
+        Err(e) => return uncached(Badge::error(e.message_code())), // Early return from the handler.
+    };
+    if let Err(e) = query.validate() {
+        return uncached(Badge::error(&e)); // Early return from the handler.
+    }
+    let target = query.target.clone().unwrap_or_else(|| "HEAD".to_string());
+    if let Err(e) = validate_ref(&target) {
+        return uncached(Badge::error(e.message_code())); // Early return from the handler.
+    }
+
+    let request = JobRequest {
+        env: Default::default(),
+        username: username.to_string(),
+        reponame: reponame.to_string(),
+        repo_url,
+        target,
+        credential: None,
+        options: CountOptions::default(),
+        mode: JobMode::Count,
+    };
+    let key = request.key();
+
+    let (badge, expires) = {
+        data::preload(db, Kind::Data, &[&key]).await;
+        let mut guard = db.lock().await;
+        match guard.get(&key) {
+            Some(data) => (
+                Some(Badge::from_data(data, &query)),
+                data.verified_time + queue.freshness(data.size_bytes).verify_interval,
+            ),
+            None => (None, 0),
+        }
+    };
+
+    let curr = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap(); // @UnsafeUnwrap
+    // Note(andrew): Analysis which has just failed (e.g. repository doesn't exist) is not requested again with
+    //     every request of the badge, see 'FAILURE_MAX_AGE'.
+    let failure = queue
+        .failure(&key)
+        .await
+        .filter(|(_, failed)| failed + badge::FAILURE_MAX_AGE > curr.as_secs());
+
+    // Note(andrew): When workers are overloaded, the badge is served as it is (or as pending), and the
+    //     analysis is requested again with the next request of the badge.
+    if expires < curr.as_secs() && failure.is_none() && !queue.is_pending(&key).await {
+        let _ = queue.submit(request).await;
+    }
+
+    match (badge, failure) {
+        (Some(badge), _) if expires >= curr.as_secs() => BadgeResponse {
+            svg: badge.render(),
+            max_age: Some(expires - curr.as_secs()),
+        },
+        (Some(badge), _) => uncached(badge),
+        (None, Some((e, failed))) => BadgeResponse {
+            svg: Badge::error(e.message_code()).render(),
+            max_age: Some(failed + badge::FAILURE_MAX_AGE - curr.as_secs()),
+        },
+        (None, None) => uncached(Badge::pending(&query)),
+    }
+}
+
+// Note(andrew): Jobs that were using server-side credentials are only visible to callers who are allowed
+//     to use the same credentials. Everyone else gets 'not found', so we don't even reveal that such job
+//     (or private repository) exists.
+fn can_see(credentials: &Credentials, caller: &CallerToken, job: &Job) -> bool {
+    match &job.credential {
+        Some(name) => credentials.is_authorized(name, caller),
+        None => true,
+    }
+}
+
+fn job_not_found(id: u64) -> Value {
+    json!({
+        "status": 404, "message_code": "err_job_not_found",
+        "message": format!("Job with id '{}' was not found (it might have expired).", id),
+    })
+}
+
+// Note(andrew): Server-Sent Events stream of the job progress, which is an alternative to polling job
+//     status. First event ('status') is the current state of the job, and then every phase of the
+//     analysis is sent as a separate event ('started', 'cloning', 'counting', 'cleaning_up'), until
+//     the job is finished with either 'done' (including analysis result) or 'error' event, after which
+//     the stream is closed. Every event carries the same payload as 'GET /api/jobs/<id>'.
+#[get("/jobs/<id>/events")]
+pub async fn get_klocc_job_events(
+    db: &State<Arc<Database>>,
+    queue: &State<JobQueue>,
+    credentials: &State<Credentials>,
+    caller: CallerToken,
+    id: u64,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], ApiResponse> {
+    let (job, mut events) = match queue.subscribe(id).await {
+        Some((job, events)) if can_see(credentials, &caller, &job) => (job, events),
+        _ => return Err(job_not_found(id).into()), // Early return from the handler.
+    };
+
+    // Note(andrew): Database has to be moved into the stream, since the stream outlives this handler.
+    let db = db.inner().clone();
+    Ok(EventStream! {
+        let finished = job.status.is_finished();
+        yield Event::json(&job_response(&db, job).await).event("status");
+        if finished {
+            return; // Nothing else is going to happen with this job.
+        }
+
+        loop {
+            let event = select! {
+                event = events.recv() => event,
+                _ = &mut shutdown => break,
+            };
+
+            match event {
+                Ok(event) => {
+                    let kind = event.kind;
+                    // @SafeUnwrap: Event kind is a plain enum, which always serializes into a string.
+                    let name = json!(kind).as_str().unwrap().to_string();
+                    yield Event::json(&job_response(&db, event.job).await).event(name);
+
+                    if kind == JobEventKind::Done || kind == JobEventKind::Error {
+                        break;
+                    }
+                }
+                // Note(andrew): We are too slow to keep up with events, which is not a big deal, since
+                //     every event carries full job state anyway, so we just skip missed ones.
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    })
+}
+
+// Build the response for the given error, with message code and status code specific to the error kind.
+fn error_response(error: &KloccError) -> Value {
+    let mut body =
+        json!({ "status": error.status(), "message_code": error.message_code(), "message": error.to_string() });
+    if let Some(seconds) = error.retry_after() {
+        body["retry_after"] = json!(seconds);
+    }
+    body
+}
+
+// Build the response for the given job, attaching analysis result from the cache, if job is done.
+async fn job_response(db: &Database, job: Job) -> Value {
+    job_response_with(db, job, None).await
+}
+
+// Same as 'job_response', but the result of the line counting job is the directory tree of the given depth.
+async fn job_response_with(db: &Database, job: Job, tree: Option<u32>) -> Value {
+    // Note(andrew): Status of the failed job is the status of the error, so the callee can tell apart
+    //     failures that are worth retrying (e.g. 502 or 504) from the ones that are not (e.g. 404).
+    let status = match (&job.status, &job.error) {
+        (JobStatus::Queued, _) => 202,
+        (JobStatus::Failed, Some(error)) => error.status(),
+        _ => 200,
+    };
+
+    if job.status != JobStatus::Done {
+        return json!({
+            "status": status, "message_code": job.message_code, "message": job.message,
+            "data": {"job": job, "result": null},
+        }); // Early return from the handler.
     }
 
     // Note(andrew): Lock the guard temporarily here, as we are going to query database for our data
-    //     reference, and safely unwrap it directly into the json, because we know it must be present,
-    //     as we just added it right above this code block (we only reach here after adding new data).
-    let guard = db.lock().await;
+    //     reference, and write it directly into the json. Job is only marked as done after the result
+    //     is stored, so it must be present.
+    match &job.output {
+        JobOutput::Data => data::preload(db, Kind::Data, &[&job.key]).await,
+        JobOutput::Timeline(samples) => {
+            let keys: Vec<&str> = samples.iter().map(|sample| sample.key.as_str()).collect();
+            data::preload(db, Kind::Data, &keys).await
+        }
+        JobOutput::Diff(base, head) => data::preload(db, Kind::Data, &[&base.key, &head.key]).await,
+        JobOutput::Activity => data::preload(db, Kind::Activity, &[&job.key]).await,
+    };
+    let mut guard = db.lock().await;
+    let result = match &job.output {
+        JobOutput::Data => match (guard.get(&job.key), tree) {
+            (Some(data), Some(depth)) => json!(tree::build(data, depth)),
+            (data, _) => json!(data),
+        },
+        JobOutput::Timeline(samples) => timeline::series(guard.as_mut(), samples),
+        // Note(andrew): Results of either side might be gone from the cache by now, in which case there
+        //     is nothing to compare, and the callee has to request the diff again.
+        //
+        //     Both sides are read first (which brings them into memory, see 'Storage'), so they can be
+        //     borrowed together afterwards.
+        JobOutput::Diff(base, head) => {
+            let found = guard.get(&base.key).is_some() && guard.get(&head.key).is_some();
+            match (found, guard.peek(&base.key), guard.peek(&head.key)) {
+                (true, Some(base_data), Some(head_data)) => diff::compare(base, base_data, head, head_data),
+                _ => Value::Null,
+            }
+        }
+        JobOutput::Activity => json!(guard.get_activity(&job.key)),
+    };
     json!({
-        "status": 200, "message_code": "info_success_generated",
-        "message": "The repo was analyzed successfully and result was stored for later reference.",
-        "data": guard.get(&repo_url).unwrap(),  // @SafeUnwrap: Data must be present, because we inserted it previously.
+        "status": status, "message_code": job.message_code, "message": job.message,
+        "data": {"job": job, "result": result},
     })
 }
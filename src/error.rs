@@ -0,0 +1,132 @@
+use std::fmt;
+
+// Note(andrew): Every failure of the service, that can reach the callee. Each kind has its own message
+//     code and HTTP status code, so clients can tell "this repository does not exist" apart from "our
+//     server is having a bad day", and act accordingly (e.g. show an error vs retry later). The value of
+//     each variant is a human-readable explanation of what exactly went wrong.
+#[derive(Clone, Debug)]
+pub enum KloccError {
+    // Provider is not in the registry (see 'Providers').
+    UnsupportedProvider(String),
+    // Username or reponame are not valid parts of the url.
+    InvalidRepository(String),
+    // Analysis options from the request are not valid (e.g. malformed exclude pattern).
+    InvalidOptions(String),
+    RepoNotFound(String),
+    // Branch, tag or commit does not exist in the repository.
+    RefNotFound(String),
+    // Repository is private, and we don't have (or were not allowed to use) credentials for it.
+    AuthRequired(String),
+    // Git server could not be reached or failed for any other reason.
+    FetchFailed(String),
+    CloneTimeout(String),
+    DiskQuotaExceeded(String),
+    // Repository is over the size (or file count) limit of the server (see 'Limits').
+    RepoTooLarge(String),
+    // Too many jobs are already waiting for the workers, so the new one is not accepted (see 'JobQueue').
+    Overloaded(String),
+    TokeiFailure(String),
+    Internal(String),
+}
+
+impl KloccError {
+    pub fn message_code(&self) -> &'static str {
+        match self {
+            KloccError::UnsupportedProvider(_) => "err_bad_service",
+            KloccError::InvalidRepository(_) => "err_bad_repository",
+            KloccError::InvalidOptions(_) => "err_bad_options",
+            KloccError::RepoNotFound(_) => "err_repo_not_found",
+            KloccError::RefNotFound(_) => "err_ref_not_found",
+            KloccError::AuthRequired(_) => "err_auth_required",
+            KloccError::FetchFailed(_) => "err_failed_to_fetch_from_repo",
+            KloccError::CloneTimeout(_) => "err_clone_timeout",
+            KloccError::DiskQuotaExceeded(_) => "err_disk_quota_exceeded",
+            KloccError::RepoTooLarge(_) => "err_repo_too_large",
+            KloccError::Overloaded(_) => "err_overloaded",
+            KloccError::TokeiFailure(_) => "err_counter_failed",
+            KloccError::Internal(_) => "err_internal",
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        match self {
+            KloccError::UnsupportedProvider(_) | KloccError::InvalidRepository(_) | KloccError::InvalidOptions(_) => 400,
+            KloccError::AuthRequired(_) => 401,
+            KloccError::RepoTooLarge(_) => 413,
+            KloccError::RepoNotFound(_) | KloccError::RefNotFound(_) => 404,
+            KloccError::FetchFailed(_) => 502,
+            KloccError::Overloaded(_) => 503,
+            KloccError::CloneTimeout(_) => 504,
+            KloccError::DiskQuotaExceeded(_) => 507,
+            KloccError::TokeiFailure(_) | KloccError::Internal(_) => 500,
+        }
+    }
+
+    // Seconds the callee should wait before retrying the request, for the errors that are expected to go away
+    // on their own (sent as the 'Retry-After' header, see 'ApiResponse').
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            KloccError::Overloaded(_) => Some(30),
+            _ => None,
+        }
+    }
+
+    // Note(andrew): Git doesn't give us anything better than the exit code and the error message, so
+    //     here we are guessing the kind of failure from the well-known messages of git itself and of
+    //     major providers. Anything we don't recognize is reported as a generic fetch failure, with
+    //     git output attached, so at least the callee can see what happened.
+    pub fn from_git_stderr(context: &str, stderr: &str) -> Self {
+        let lower = stderr.to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|p| lower.contains(p));
+        let message = format!("{}: {}", context, stderr.trim());
+
+        if has(&["no space left on device", "disk quota exceeded"]) {
+            KloccError::DiskQuotaExceeded(message)
+        } else if has(&["timed out", "timeout"]) {
+            KloccError::CloneTimeout(message)
+        } else if has(&[
+            "could not read username",
+            "could not read password",
+            "authentication failed",
+            "permission denied (publickey)",
+            "access denied",
+        ]) {
+            // Note(andrew): GitHub asks for credentials for repositories that don't exist as well, so
+            //     this is also what nonexistent repository looks like for anonymous callers.
+            KloccError::AuthRequired(message)
+        } else if has(&["remote branch", "couldn't find remote ref", "not our ref"]) {
+            // Note(andrew): This has to go before the check below, since git says "remote branch
+            //     'x' not found" for missing branches and tags.
+            KloccError::RefNotFound(message)
+        } else if has(&[
+            "repository not found",
+            "not found",
+            "does not appear to be a git repository",
+            "does not exist",
+        ]) {
+            KloccError::RepoNotFound(message)
+        } else {
+            KloccError::FetchFailed(message)
+        }
+    }
+}
+
+impl fmt::Display for KloccError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KloccError::UnsupportedProvider(msg)
+            | KloccError::InvalidRepository(msg)
+            | KloccError::InvalidOptions(msg)
+            | KloccError::RepoNotFound(msg)
+            | KloccError::RefNotFound(msg)
+            | KloccError::AuthRequired(msg)
+            | KloccError::FetchFailed(msg)
+            | KloccError::CloneTimeout(msg)
+            | KloccError::DiskQuotaExceeded(msg)
+            | KloccError::RepoTooLarge(msg)
+            | KloccError::Overloaded(msg)
+            | KloccError::TokeiFailure(msg)
+            | KloccError::Internal(msg) => write!(f, "{}", msg),
+        }
+    }
+}
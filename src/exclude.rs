@@ -0,0 +1,186 @@
+use ignore::Match;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use rocket::serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+use crate::error::KloccError;
+
+// Name of the repository-level file with patterns of files that should not be counted. It uses the same
+// syntax as '.gitignore'.
+const KLOCCIGNORE: &str = ".kloccignore";
+
+// Note(andrew): Amount of files that were excluded from the analysis, by the reason of the exclusion.
+//     Every file is counted only once, in the first matching category in the order of fields below,
+//     i.e. patterns from the request take precedence over anything from the repository itself.
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct ExcludedInfo {
+    // Matched by one of the 'exclude' patterns from the request.
+    pub requested: u32,
+    // Matched by one of the patterns in the '.kloccignore'.
+    pub ignored: u32,
+    // Marked with 'linguist-vendored', 'linguist-generated' or 'linguist-documentation' in '.gitattributes'.
+    pub vendored: u32,
+    pub generated: u32,
+    pub documentation: u32,
+}
+
+// Build matcher from the given gitignore-like lines, where the 'source' is used in error messages.
+fn build<'a>(root: &Path, source: &str, lines: impl Iterator<Item = &'a str>) -> Result<Gitignore, String> {
+    let mut builder = GitignoreBuilder::new(root);
+    for line in lines {
+        if let Err(e) = builder.add_line(None, line) {
+            return Err(format!("Invalid pattern '{}' in {}: {}", line, source, e));
+        }
+    }
+    builder
+        .build()
+        .map_err(|e| format!("Invalid patterns in {}: {}", source, e))
+}
+
+// Check that patterns from the request are valid, so we can reject bad request right away instead of
+// failing the job later.
+pub fn validate(patterns: &[String]) -> Result<(), KloccError> {
+    match build(Path::new("/"), "request", patterns.iter().map(String::as_str)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(KloccError::InvalidOptions(e)),
+    }
+}
+
+// Note(andrew): Translate '.gitattributes' into the gitignore-like lines for every linguist attribute
+//     that we care about. Each line of '.gitattributes' is a pattern followed by attributes, where the
+//     attribute can be set ('attr' or 'attr=true'), or unset ('-attr', '!attr' or 'attr=false'). Unset
+//     is translated into the negated ('!pattern') line, so it overrides earlier lines, just like later
+//     lines override earlier ones in '.gitattributes'. Only the top-level '.gitattributes' is read, and
+//     macro attributes (e.g. 'binary') are not expanded.  @Incomplete
+fn gitattributes_lines(content: &str, attribute: &str) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.split_whitespace();
+        let pattern = match parts.next() {
+            Some(value) => value,
+            None => continue,
+        };
+
+        for attr in parts {
+            let set = match attr.strip_prefix('-').or_else(|| attr.strip_prefix('!')) {
+                Some(name) if name == attribute => Some(false),
+                Some(_) => None,
+                None => match attr.split_once('=') {
+                    Some((name, value)) if name == attribute => Some(value != "false"),
+                    Some(_) => None,
+                    None => (attr == attribute).then_some(true),
+                },
+            };
+
+            match set {
+                Some(true) => lines.push(pattern.to_string()),
+                Some(false) => lines.push(format!("!{}", pattern)),
+                None => (),
+            }
+        }
+    }
+
+    lines
+}
+
+// Set of matchers for every kind of exclusion, in the order of precedence (see 'ExcludedInfo').
+pub struct Exclusions {
+    requested: Gitignore,
+    ignored: Gitignore,
+    vendored: Gitignore,
+    generated: Gitignore,
+    documentation: Gitignore,
+}
+
+impl Exclusions {
+    // Build exclusions for the repository cloned at 'root', with additional patterns from the request.
+    // Broken files in the repository are not fatal, since it's not the callee's fault, so they are
+    // only logged and ignored.
+    pub fn new(root: &Path, requested: &[String]) -> Result<Self, KloccError> {
+        let read = |name: &str| fs::read_to_string(root.join(name)).unwrap_or_default();
+        let or_empty = |result: Result<Gitignore, String>| match result {
+            Ok(value) => value,
+            Err(e) => {
+                info!("Ignoring exclusions of {:?}: {}", root, e);
+                Gitignore::empty()
+            }
+        };
+
+        let kloccignore = read(KLOCCIGNORE);
+        let gitattributes = read(".gitattributes");
+        let linguist = |attribute: &str| {
+            let lines = gitattributes_lines(&gitattributes, attribute);
+            or_empty(build(root, ".gitattributes", lines.iter().map(String::as_str)))
+        };
+
+        Ok(Self {
+            requested: build(root, "request", requested.iter().map(String::as_str))
+                .map_err(KloccError::InvalidOptions)?,
+            ignored: or_empty(build(root, KLOCCIGNORE, kloccignore.lines())),
+            vendored: linguist("linguist-vendored"),
+            generated: linguist("linguist-generated"),
+            documentation: linguist("linguist-documentation"),
+        })
+    }
+
+    // Check whether file at given path is excluded, and count it in the corresponding category. Path is
+    // expected to be inside the 'root'.
+    pub fn check(&self, path: &Path, excluded: &mut ExcludedInfo) -> bool {
+        let matched = |matcher: &Gitignore| matches!(matcher.matched_path_or_any_parents(path, false), Match::Ignore(_));
+
+        let counter = if matched(&self.requested) {
+            &mut excluded.requested
+        } else if matched(&self.ignored) {
+            &mut excluded.ignored
+        } else if matched(&self.vendored) {
+            &mut excluded.vendored
+        } else if matched(&self.generated) {
+            &mut excluded.generated
+        } else if matched(&self.documentation) {
+            &mut excluded.documentation
+        } else {
+            return false;
+        };
+
+        *counter += 1;
+        true
+    }
+
+    // Same as 'check', but without counting (i.e. for the files that were already counted).
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.check(path, &mut ExcludedInfo::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gitattributes_lines_negate_unset_attributes() {
+        let content = "\
+            # Vendored, except for our own fork.\n\
+            third_party/** linguist-vendored\n\
+            third_party/fork/** -linguist-vendored\n\
+            deps/** !linguist-vendored linguist-generated\n\
+            lib/** linguist-vendored=false\n\
+            *.js text eol=lf -linguist-generated\n";
+
+        assert_eq!(
+            gitattributes_lines(content, "linguist-vendored"),
+            vec!["third_party/**", "!third_party/fork/**", "!deps/**", "!lib/**"]
+        );
+        assert_eq!(
+            gitattributes_lines(content, "linguist-generated"),
+            vec!["deps/**", "!*.js"]
+        );
+        assert!(gitattributes_lines(content, "linguist-documentation").is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+use rocket::http::{ContentType, MediaType};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::Serialize;
+use rocket::serde::json::to_string;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use tokei::{CodeStats, Language, LanguageType, Languages, Report};
+
+use crate::data::{Data, FileInfo, Info};
+use crate::error::KloccError;
+
+// Note(andrew): Format of the analysis result in the response, for the callers migrating from the cloc and
+//     tokei CLIs, who want to keep their pipelines as they are. It is picked by the 'format' query parameter,
+//     or by the 'Accept' header (when the query parameter is missing), and our own json envelope is the
+//     default. Only the line counts are rendered in those formats (see 'render'), everything else (e.g.
+//     errors, or jobs that are not done yet) is always the envelope.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Envelope,
+    Csv,
+    Tokei,
+    ClocYaml,
+    ClocXml,
+}
+
+impl Format {
+    fn from_query(value: &str) -> Result<Self, KloccError> {
+        match value.to_lowercase().as_str() {
+            "json" => Ok(Format::Envelope),
+            "csv" => Ok(Format::Csv),
+            "tokei" => Ok(Format::Tokei),
+            "cloc-yaml" | "yaml" => Ok(Format::ClocYaml),
+            "cloc-xml" | "xml" => Ok(Format::ClocXml),
+            _ => Err(KloccError::InvalidOptions(format!(
+                "Unknown format '{}', expected 'json', 'csv', 'tokei', 'cloc-yaml' or 'cloc-xml'.",
+                value
+            ))),
+        }
+    }
+
+    // Note(andrew): There is no media type for tokei json, so it can only be picked with the query parameter,
+    //     and anything we don't know (including '*/*' and 'application/json') is the envelope.
+    fn from_media_type(media: &MediaType) -> Self {
+        match (media.top().as_str(), media.sub().as_str()) {
+            ("text", "csv") => Format::Csv,
+            ("application" | "text", "yaml" | "x-yaml") => Format::ClocYaml,
+            ("application" | "text", "xml") => Format::ClocXml,
+            _ => Format::Envelope,
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Format::Envelope | Format::Tokei => ContentType::JSON,
+            Format::Csv => ContentType::CSV,
+            Format::ClocYaml => ContentType::new("application", "yaml"),
+            Format::ClocXml => ContentType::XML,
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Format {
+    type Error = KloccError;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        if let Some(Ok(value)) = req.query_value::<&str>("format") {
+            return match Format::from_query(value) {
+                Ok(format) => Outcome::Success(format),
+                Err(e) => Outcome::Error((rocket::http::Status::BadRequest, e)),
+            }; // Early return.
+        }
+
+        let format = match req.accept() {
+            Some(accept) => Format::from_media_type(accept.preferred().media_type()),
+            None => Format::Envelope,
+        };
+        Outcome::Success(format)
+    }
+}
+
+fn lines(info: &Info) -> u32 {
+    info.code + info.comments + info.blanks
+}
+
+// Note(andrew): Language totals, the way cloc would report them. Cloc doesn't know about embedded code, and
+//     counts all lines of the file for the language of the file, so embedded code goes back into the total
+//     of the language it is embedded into (unless it was excluded from the total, see 'CountOptions').
+fn cloc_languages(data: &Data) -> Vec<(&str, usize, Info)> {
+    let mut languages: Vec<(&str, usize, Info)> = data
+        .languages
+        .iter()
+        .map(|lang| {
+            let mut total = Info::new(lang.total.code, lang.total.comments, lang.total.blanks);
+            if data.options.include_embedded {
+                for child in lang.children.iter() {
+                    total.code += child.total.code;
+                    total.comments += child.total.comments;
+                    total.blanks += child.total.blanks;
+                }
+            }
+            (lang.name.as_str(), lang.files.len(), total)
+        })
+        .collect();
+    languages.sort_by_key(|(_, _, total)| std::cmp::Reverse(total.code));
+    languages
+}
+
+fn cloc_sum(languages: &[(&str, usize, Info)]) -> (usize, Info) {
+    let mut sum = Info::default();
+    for (_, _, total) in languages {
+        sum.code += total.code;
+        sum.comments += total.comments;
+        sum.blanks += total.blanks;
+    }
+    (languages.iter().map(|(_, files, _)| files).sum(), sum)
+}
+
+fn render_cloc_yaml(data: &Data) -> String {
+    let languages = cloc_languages(data);
+    let (files, sum) = cloc_sum(&languages);
+
+    let mut out = String::from("---\n");
+    out += "header :\n";
+    out += &format!("  n_files : {}\n  n_lines : {}\n", files, lines(&sum));
+    // Names are quoted, since some of them are not valid plain yaml scalars (e.g. 'F#' or 'C++').
+    for (name, files, total) in languages.iter() {
+        out += &format!("{} :\n", to_string(name).unwrap_or_default());
+        out += &format!(
+            "  nFiles: {}\n  blank: {}\n  comment: {}\n  code: {}\n",
+            files, total.blanks, total.comments, total.code
+        );
+    }
+    out += &format!(
+        "SUM:\n  blank: {}\n  comment: {}\n  code: {}\n  nFiles: {}\n",
+        sum.blanks, sum.comments, sum.code, files
+    );
+    out
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn render_cloc_xml(data: &Data) -> String {
+    let languages = cloc_languages(data);
+    let (files, sum) = cloc_sum(&languages);
+
+    let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?><results>\n");
+    out += &format!(
+        "<header>\n  <n_files>{}</n_files>\n  <n_lines>{}</n_lines>\n</header>\n",
+        files,
+        lines(&sum)
+    );
+    out += "<languages>\n";
+    for (name, files, total) in languages.iter() {
+        out += &format!(
+            "  <language name=\"{}\" files_count=\"{}\" blank=\"{}\" comment=\"{}\" code=\"{}\" />\n",
+            escape_xml(name),
+            files,
+            total.blanks,
+            total.comments,
+            total.code
+        );
+    }
+    out += &format!(
+        "  <total sum_files=\"{}\" blank=\"{}\" comment=\"{}\" code=\"{}\" />\n",
+        files, sum.blanks, sum.comments, sum.code
+    );
+    out += "</languages>\n</results>\n";
+    out
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// One row per file, where embedded code (see 'LanguageInfo::children') has its own rows, with the language
+// of the file it is embedded into in the 'embedded_in' column.
+fn render_csv(data: &Data) -> String {
+    let mut out = String::from("language,embedded_in,path,code,comments,blanks\n");
+    let mut row = |language: &str, parent: &str, file: &FileInfo| {
+        out += &format!(
+            "{},{},{},{},{},{}\n",
+            escape_csv(language),
+            escape_csv(parent),
+            escape_csv(&file.path),
+            file.code,
+            file.comments,
+            file.blanks
+        );
+    };
+
+    for lang in data.languages.iter() {
+        for file in lang.files.iter() {
+            row(&lang.name, "", file);
+        }
+        for child in lang.children.iter() {
+            for file in child.files.iter() {
+                row(&child.name, &lang.name, file);
+            }
+        }
+    }
+    out
+}
+
+// Same layout as the json output of tokei CLI (i.e. 'tokei --output json').
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct TokeiOutput {
+    #[serde(flatten)]
+    languages: BTreeMap<LanguageType, Language>,
+    #[serde(rename = "Total")]
+    totals: Language,
+}
+
+fn tokei_report(file: &FileInfo) -> Report {
+    let mut report = Report::new(PathBuf::from(format!("./{}", file.path)));
+    report.stats = CodeStats::new();
+    report.stats.code = file.code as usize;
+    report.stats.comments = file.comments as usize;
+    report.stats.blanks = file.blanks as usize;
+    report
+}
+
+// Note(andrew): Tokei results are rebuilt from our per-file data, which is lossy in one way: embedded code is
+//     only listed in the 'children' of the language, and not in the 'blobs' of the file it is embedded into,
+//     since we don't keep it per file of the parent language.  @Incomplete
+fn render_tokei(data: &Data) -> String {
+    let mut languages = Languages::new();
+    for lang in data.languages.iter() {
+        // Names are the ones tokei gave us in the first place, so they are always known.
+        let language_type = match LanguageType::from_str(&lang.name) {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+
+        let mut language = Language::new();
+        for file in lang.files.iter() {
+            language.add_report(tokei_report(file));
+        }
+        for child in lang.children.iter() {
+            if let Ok(child_type) = LanguageType::from_str(&child.name) {
+                language
+                    .children
+                    .insert(child_type, child.files.iter().map(tokei_report).collect());
+            }
+        }
+        language.total();
+        languages.insert(language_type, language);
+    }
+
+    let totals = languages.total();
+    let output = TokeiOutput {
+        languages: languages.into_iter().collect(),
+        totals,
+    };
+    // @SafeUnwrap: Tokei types are plain values, which always serialize into json.
+    to_string(&output).unwrap()
+}
+
+// Render the analysis result in the given format, where the envelope is built by the endpoints themselves.
+pub fn render(data: &Data, format: Format) -> Option<String> {
+    match format {
+        Format::Envelope => None,
+        Format::Csv => Some(render_csv(data)),
+        Format::Tokei => Some(render_tokei(data)),
+        Format::ClocYaml => Some(render_cloc_yaml(data)),
+        Format::ClocXml => Some(render_cloc_xml(data)),
+    }
+}
@@ -0,0 +1,70 @@
+use rocket::serde::Deserialize;
+
+const MB: u64 = 1024 * 1024;
+
+// Row of the freshness table (see 'Freshness'), where intervals are in seconds.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct FreshnessTier {
+    // Biggest on-disk size of the repository (in megabytes) this row is for, where missing one is unbounded.
+    #[serde(default)]
+    pub max_size_mb: Option<u64>,
+    // How long the cached result is served as is, without checking the repository for new commits.
+    pub verify_interval: u64,
+    // How long the cached result is served after it was produced, even when the repository has new commits.
+    pub reanalyze_interval: u64,
+}
+
+impl FreshnessTier {
+    fn new(max_size_mb: Option<u64>, interval: u64) -> Self {
+        Self {
+            max_size_mb,
+            verify_interval: interval,
+            reanalyze_interval: interval,
+        }
+    }
+}
+
+// Note(andrew): Defaults are the ones from the TODO.txt, where 5 minutes for small repositories is what we
+//     had as the fixed verification interval from the beginning (and reanalysis of those is never held back,
+//     since verification happens later than that anyway).
+pub fn default_tiers() -> Vec<FreshnessTier> {
+    vec![
+        FreshnessTier::new(Some(10), 60 * 5),
+        FreshnessTier::new(Some(100), 60 * 30),
+        FreshnessTier::new(Some(1000), 60 * 60 * 6),
+        FreshnessTier::new(None, 60 * 60 * 24),
+    ]
+}
+
+// Note(andrew): How long cached results are considered fresh (see 'post_klocc_job'), depending on the size of
+//     the repository on disk (see 'Data::size_bytes'). Big repositories (e.g. torvalds/linux) take a lot of
+//     time to analyze, and are changing all the time, so we don't reanalyze them more often than the
+//     'reanalyze_interval', serving the result of the older commit in the meantime.
+#[derive(Clone, Debug)]
+pub struct Freshness {
+    // Sorted from the smallest size to the biggest one, where unbounded row is the last one.
+    tiers: Vec<FreshnessTier>,
+}
+
+impl Freshness {
+    pub fn new(tiers: &[FreshnessTier]) -> Self {
+        let mut tiers = match tiers.is_empty() {
+            true => default_tiers(),
+            false => tiers.to_vec(),
+        };
+        tiers.sort_by_key(|tier| tier.max_size_mb.unwrap_or(u64::MAX));
+        Self { tiers }
+    }
+
+    // Find the row for the repository of the given size, where sizes beyond the biggest row are treated the
+    // same as the biggest row (i.e. when operator didn't configure the unbounded one).
+    pub fn tier(&self, size_bytes: u64) -> &FreshnessTier {
+        let tier = self
+            .tiers
+            .iter()
+            .find(|tier| tier.max_size_mb.is_none_or(|max| size_bytes <= max.saturating_mul(MB)));
+        // @SafeUnwrap: Table is never empty, see 'new'.
+        tier.unwrap_or_else(|| self.tiers.last().unwrap())
+    }
+}
@@ -0,0 +1,832 @@
+use rocket::serde::Serialize;
+use rocket::tokio::sync::{Mutex, broadcast, mpsc};
+use rocket::tokio::task;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use crate::counter::{GitEnv, Phase, fetch_history, get_activity_from_repo, get_data_from_repo, get_latest_hash};
+use crate::data::{self, CountOptions, Database, cache_key};
+use crate::diff::Side;
+use crate::error::KloccError;
+use crate::freshness::{Freshness, FreshnessTier};
+use crate::limits::Limits;
+use crate::prom::{ACTIVE_JOBS, COALESCED_REQUESTS, QUEUED_JOBS, TOTAL_REPOSITORIES_SERVED};
+use crate::storage::Kind;
+use crate::timeline::{self, Sample, TimelineOptions};
+
+// Note(andrew): Finished jobs (either done or failed) are kept around for this amount of seconds,
+//     so the frontend has plenty of time to come back for the result. After that, job is forgotten
+//     and querying it returns 'not found'. Note that the analysis result itself lives in the cache
+//     and does not expire together with the job.
+const JOB_RETENTION: u64 = 60 * 60;
+
+// Jobs which are finished at the moment of creation (i.e. result was found in the cache) are only kept
+// for this amount of seconds, since the result is already in the response, and there are lots of them.
+const CACHED_JOB_RETENTION: u64 = 60;
+
+// Amount of progress events buffered for each subscriber of the job, before it starts missing them.
+const JOB_EVENTS_CAPACITY: usize = 16;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Cloning,
+    Counting,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobStatus::Done | JobStatus::Failed)
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Job {
+    pub id: u64,
+    pub repo: String,
+    #[serde(rename = "ref")]
+    pub target: String,
+    pub status: JobStatus,
+    pub message_code: String,
+    pub message: String,
+    // The reason why the job has failed, only present for failed jobs.
+    #[serde(skip)]
+    pub error: Option<KloccError>,
+    pub creation_time: u64,
+    pub updated_time: u64,
+    // Key of the analysis result in the cache, which is valid only when the job is done.
+    #[serde(skip)]
+    pub key: String,
+    // Name of the server-side credential used for this job (see 'Credentials'), if any. Such jobs are
+    // only visible to callers who are authorized to use the same credential.
+    #[serde(skip)]
+    pub credential: Option<String>,
+    // Base ref of the comparison, only present for diff jobs (see 'JobMode::Diff').
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    // What the result of the job is made of, which is only known once the job is done.
+    #[serde(skip)]
+    pub output: JobOutput,
+    // Progress events of this job are broadcasted here, see 'JobQueue::subscribe'. Only present until the
+    // job is finished, since nothing else is going to happen with it after that.
+    #[serde(skip)]
+    events: Option<broadcast::Sender<JobEvent>>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum JobEventKind {
+    Started,
+    Cloning,
+    Counting,
+    CleaningUp,
+    Done,
+    Error,
+}
+
+// Note(andrew): Most jobs produce a single analysis result, which is stored in the cache under 'Job::key', but
+//     timelines and diffs are made of results of multiple commits, which are stored separately (under the
+//     keys of those commits), and combined into the response on the fly (see 'job_response'). Activity is
+//     stored under 'Job::key' as well, but separately from the line counts (see 'Storage').
+#[derive(Clone, Debug, Default)]
+pub enum JobOutput {
+    #[default]
+    Data,
+    Timeline(Vec<Sample>),
+    Diff(Side, Side),
+    Activity,
+}
+
+// Progress event of the job, along with the job state at the moment of the event.
+#[derive(Clone, Debug)]
+pub struct JobEvent {
+    pub kind: JobEventKind,
+    pub job: Job,
+}
+
+// Everything the worker needs to know to process the job.
+#[derive(Clone)]
+pub struct JobRequest {
+    pub username: String,
+    pub reponame: String,
+    pub repo_url: String,
+    pub target: String,
+    pub credential: Option<String>,
+    pub options: CountOptions,
+    pub mode: JobMode,
+    // Environment for the git commands, with secrets of the credential (see 'Credentials::git_env').
+    pub env: GitEnv,
+}
+
+// What kind of analysis the job is doing.
+#[derive(Clone)]
+pub enum JobMode {
+    // Count lines at the target ref.
+    Count,
+    // Count lines at multiple commits of the history, instead of only at the target ref.
+    Timeline(TimelineOptions),
+    // Compare the target ref with this one (base ref), instead of only counting lines at the target ref.
+    Diff(String),
+    // Commit activity of the target ref history (see 'Activity').
+    Activity,
+}
+
+impl JobRequest {
+    pub fn key(&self) -> String {
+        cache_key(&self.repo_url, &self.target, self.credential.as_deref(), &self.options)
+    }
+}
+
+struct Task {
+    id: u64,
+    request: JobRequest,
+}
+
+// Table of the jobs, along with the order in which finished jobs are forgotten.
+#[derive(Default)]
+struct Jobs {
+    table: HashMap<u64, Job>,
+    // Finished jobs by the time they are forgotten at (and by id), soonest first.
+    expiry: BTreeSet<(u64, u64)>,
+    // Amount of unfinished jobs for each cache key (see 'JobQueue::is_pending').
+    pending: HashMap<String, usize>,
+    // The last failed job for each cache key, unless some job for the same key is done after it.
+    failed: HashMap<String, u64>,
+}
+
+impl Jobs {
+    fn insert(&mut self, job: Job) {
+        if job.status.is_finished() {
+            self.expiry.insert((job.updated_time + CACHED_JOB_RETENTION, job.id));
+        } else {
+            *self.pending.entry(job.key.clone()).or_default() += 1;
+        }
+        self.table.insert(job.id, job);
+    }
+
+    // Get the job which is being finished (either done or failed), so it is forgotten after 'JOB_RETENTION'
+    // from now. Nothing is returned for the job which is already finished.
+    fn finish(&mut self, id: u64, failed: bool) -> Option<&mut Job> {
+        let job = self.table.get_mut(&id).filter(|job| !job.status.is_finished())?;
+        job.updated_time = now();
+        self.expiry.insert((job.updated_time + JOB_RETENTION, id));
+
+        if let Some(count) = self.pending.get_mut(&job.key) {
+            *count -= 1;
+            if *count == 0 {
+                self.pending.remove(&job.key);
+            }
+        }
+        if failed {
+            self.failed.insert(job.key.clone(), id);
+        } else {
+            self.failed.remove(&job.key);
+        }
+        Some(job)
+    }
+
+    // Forget finished jobs, which were kept around for long enough.
+    fn expire(&mut self, curr: u64) {
+        while let Some(&(expires, id)) = self.expiry.first() {
+            if expires >= curr {
+                break;
+            }
+            self.expiry.pop_first();
+            if let Some(job) = self.table.remove(&id)
+                && self.failed.get(&job.key) == Some(&id)
+            {
+                self.failed.remove(&job.key);
+            }
+        }
+    }
+}
+
+// Outcome of the analysis (message code and message, or the error), shared by all jobs of the same flight
+// (see 'JobQueue::take_off').
+type Outcome = Result<(&'static str, String), KloccError>;
+
+struct Inner {
+    db: Arc<Database>,
+    freshness: Freshness,
+    limits: Limits,
+    jobs: Mutex<Jobs>,
+    // Analyses in progress (see 'JobQueue::take_off'), along with the jobs that have joined them.
+    flights: Mutex<HashMap<String, Vec<u64>>>,
+    next_id: AtomicU64,
+    // Note(andrew): Channel is bounded, so a burst of new repositories can't pile up an endless amount of
+    //     work (which nobody is going to wait for anyway), and callees are asked to come back later instead.
+    sender: mpsc::Sender<Task>,
+    // Note(andrew): Tokio channel has a single consumer, so workers take turns on the receiver. The
+    //     lock is only held while waiting for the next task, not while processing it.
+    receiver: Mutex<mpsc::Receiver<Task>>,
+}
+
+// Note(andrew): Queue of analysis jobs, processed by a fixed amount of workers in the background.
+//     Endpoints only create jobs and read their status, so the HTTP request is never held open for
+//     the duration of clone and count. This is cheap to clone, since all clones share the same state.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<Inner>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() // @UnsafeUnwrap
+}
+
+impl JobQueue {
+    pub fn new(db: Arc<Database>, freshness: Freshness, limits: Limits, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(queue_size.max(1));
+        let inner = Inner {
+            db,
+            freshness,
+            limits,
+            jobs: Mutex::new(Jobs::default()),
+            flights: Mutex::new(HashMap::new()),
+            next_id: AtomicU64::new(1),
+            sender,
+            receiver: Mutex::new(receiver),
+        };
+
+        Self { inner: Arc::new(inner) }
+    }
+
+    // Spawn given amount of workers, each of them processing one job at a time. This has to be
+    // called from within the async runtime (i.e. after rocket has launched).
+    pub fn start(&self, workers: usize) {
+        for _ in 0..workers.max(1) {
+            let queue = self.clone();
+            rocket::tokio::spawn(async move {
+                loop {
+                    // Note(andrew): Binding the task first, so the receiver lock is released before
+                    //     we start processing it, and other workers can pick up next tasks.
+                    let task = queue.inner.receiver.lock().await.recv().await;
+                    match task {
+                        Some(task) => {
+                            QUEUED_JOBS.dec();
+                            ACTIVE_JOBS.inc();
+                            queue.process(task).await;
+                            ACTIVE_JOBS.dec();
+                        }
+                        None => break, // Channel is closed, which means we are shutting down.
+                    }
+                }
+            });
+        }
+    }
+
+    // Freshness of the cached results of the repository of the given size (see 'Freshness').
+    pub fn freshness(&self, size_bytes: u64) -> &FreshnessTier {
+        self.inner.freshness.tier(size_bytes)
+    }
+
+    pub async fn get(&self, id: u64) -> Option<Job> {
+        self.inner.jobs.lock().await.table.get(&id).cloned()
+    }
+
+    // Check whether there is an unfinished job for the given cache key (i.e. its result is on the way).
+    pub async fn is_pending(&self, key: &str) -> bool {
+        self.inner.jobs.lock().await.pending.contains_key(key)
+    }
+
+    // Error of the last failed job for the given cache key, along with the time it has failed at, unless some
+    // job for the same key is done after it (or the failed job is already forgotten).
+    pub async fn failure(&self, key: &str) -> Option<(KloccError, u64)> {
+        let jobs = self.inner.jobs.lock().await;
+        let job = jobs.failed.get(key).and_then(|id| jobs.table.get(id))?;
+        job.error.clone().map(|error| (error, job.updated_time))
+    }
+
+    // Register a job, which is already finished at the moment of creation (i.e. result was found in
+    // the cache), so the callee can still reference it the same way as any other job.
+    pub async fn finished(&self, request: &JobRequest, message_code: &str, message: &str) -> Job {
+        self.create(request, JobStatus::Done, message_code, message).await
+    }
+
+    // Create a new job and send it to the workers, unless there are too many jobs waiting for them already.
+    pub async fn submit(&self, request: JobRequest) -> Result<Job, KloccError> {
+        // Note(andrew): Reserving the place in the queue first, so the job is only created when it is accepted.
+        let permit = match self.inner.sender.try_reserve() {
+            Ok(value) => value,
+            Err(_) => {
+                let msg = "Too many repositories are waiting for analysis right now, please try again later.";
+                return Err(KloccError::Overloaded(msg.to_string()));
+            }
+        };
+
+        let job = self
+            .create(
+                &request,
+                JobStatus::Queued,
+                "info_job_queued",
+                "Your request was queued for analysis, check job status for the result.",
+            )
+            .await;
+
+        // Counted before sending, since the worker might pick it up right away.
+        QUEUED_JOBS.inc();
+        permit.send(Task { id: job.id, request });
+
+        Ok(job)
+    }
+
+    async fn create(&self, request: &JobRequest, status: JobStatus, message_code: &str, message: &str) -> Job {
+        let curr = now();
+        let job = Job {
+            id: self.inner.next_id.fetch_add(1, Ordering::Relaxed),
+            key: request.key(),
+            repo: request.repo_url.clone(),
+            target: request.target.clone(),
+            credential: request.credential.clone(),
+            base: match &request.mode {
+                JobMode::Diff(base) => Some(base.clone()),
+                _ => None,
+            },
+            output: match request.mode {
+                JobMode::Activity => JobOutput::Activity,
+                _ => JobOutput::Data,
+            },
+            status,
+            message_code: message_code.to_string(),
+            message: message.to_string(),
+            error: None,
+            creation_time: curr,
+            updated_time: curr,
+            events: match status.is_finished() {
+                true => None,
+                false => Some(broadcast::channel(JOB_EVENTS_CAPACITY).0),
+            },
+        };
+
+        let mut jobs = self.inner.jobs.lock().await;
+        // Forget old finished jobs while we are here, so the jobs table doesn't grow forever.
+        jobs.expire(curr);
+        jobs.insert(job.clone());
+
+        job
+    }
+
+    // Get current state of the job along with the receiver of its future progress events. Both are
+    // taken under the same lock, so no event can slip in between. Receiver of the finished job is
+    // already closed.
+    pub async fn subscribe(&self, id: u64) -> Option<(Job, broadcast::Receiver<JobEvent>)> {
+        let jobs = self.inner.jobs.lock().await;
+        jobs.table.get(&id).map(|job| {
+            let events = match &job.events {
+                Some(events) => events.subscribe(),
+                None => broadcast::channel(1).1,
+            };
+            (job.clone(), events)
+        })
+    }
+
+    // Finish the job successfully, with the given output (see 'JobOutput').
+    async fn done_with(&self, id: u64, output: JobOutput, message_code: &str, message: &str) {
+        if let Some(job) = self.inner.jobs.lock().await.finish(id, false) {
+            job.status = JobStatus::Done;
+            job.output = output;
+            job.message_code = message_code.to_string();
+            job.message = message.to_string();
+
+            // Note(andrew): Sending only fails when nobody is subscribed, which is perfectly fine. Subscribers
+            //     still get this last event after the channel is dropped.
+            if let Some(events) = job.events.take() {
+                let _ = events.send(JobEvent {
+                    kind: JobEventKind::Done,
+                    job: job.clone(),
+                });
+            }
+        }
+    }
+
+    // Finish the job with the error.
+    async fn fail(&self, id: u64, error: KloccError) {
+        if let Some(job) = self.inner.jobs.lock().await.finish(id, true) {
+            job.status = JobStatus::Failed;
+            job.message_code = error.message_code().to_string();
+            job.message = error.to_string();
+            job.error = Some(error);
+
+            if let Some(events) = job.events.take() {
+                let _ = events.send(JobEvent {
+                    kind: JobEventKind::Error,
+                    job: job.clone(),
+                });
+            }
+        }
+    }
+
+    // Report progress of the analysis. Used from the analysis thread, where we can't await on the
+    // lock, hence blocking version of it.
+    fn report_blocking(&self, id: u64, phase: Phase) {
+        if let Some(job) = self.inner.jobs.blocking_lock().table.get_mut(&id) {
+            let kind = match phase {
+                Phase::Started => JobEventKind::Started,
+                Phase::Cloning => JobEventKind::Cloning,
+                Phase::Counting => JobEventKind::Counting,
+                Phase::CleaningUp => JobEventKind::CleaningUp,
+            };
+            match phase {
+                Phase::Cloning => job.status = JobStatus::Cloning,
+                Phase::Counting => job.status = JobStatus::Counting,
+                Phase::Started | Phase::CleaningUp => (), // Job status doesn't change.
+            };
+            job.updated_time = now();
+
+            if let Some(events) = &job.events {
+                let _ = events.send(JobEvent { kind, job: job.clone() });
+            }
+        }
+    }
+
+    async fn process(&self, task: Task) {
+        let request = task.request;
+        let key = request.key();
+
+        match request.mode.clone() {
+            JobMode::Count => (),
+            JobMode::Timeline(timeline) => return self.process_timeline(task.id, request, timeline).await,
+            JobMode::Diff(base) => return self.process_diff(task.id, request, base).await,
+            JobMode::Activity => return self.process_activity(task.id, request).await,
+        };
+
+        let hash = match self.latest_hash(&request).await {
+            Ok(value) => value,
+            Err(e) => return self.fail(task.id, e).await,
+        };
+
+        let flight = format!("data {}@{}", key, hash);
+        if !self.take_off(&flight, task.id).await {
+            return; // Early return, the job is finished along with the one it has joined (see 'land').
+        }
+        let outcome = self.count(task.id, &request, &key, hash, &flight).await;
+        self.land(&flight, task.id, JobOutput::Data, outcome).await;
+    }
+
+    // Note(andrew): Only one job analyzes the same commit at a time (i.e. it is in flight), and the rest of
+    //     them (e.g. when the repository was just shared somewhere, and lots of people are opening it at
+    //     once) are joining it, instead of cloning the same repository over and over again. Joined jobs are
+    //     not taking the worker, and are getting the same progress events and the same outcome as the
+    //     leading one. Flights are keyed by the cache key and the hash of the commit, and the cache is only
+    //     checked after taking off, so the job that comes right after the landing finds the result there.
+    //
+    //     Cache key includes the requested ref, so the same commit requested by different refs (e.g. 'HEAD',
+    //     the branch name and the hash itself) is still analyzed once per ref. Timelines and diffs are not
+    //     taking off at all (see 'timeline_blocking' and 'analyze_blocking'), and only share the cache.  @Speed
+    //
+    //     Returns whether the job has to do the analysis itself.
+    async fn take_off(&self, flight: &str, id: u64) -> bool {
+        let mut flights = self.inner.flights.lock().await;
+        match flights.get_mut(flight) {
+            Some(followers) => {
+                followers.push(id);
+                COALESCED_REQUESTS.inc();
+                false
+            }
+            None => {
+                flights.insert(flight.to_string(), Vec::new());
+                true
+            }
+        }
+    }
+
+    // Finish the leading job of the flight, along with all jobs that have joined it, with the same outcome.
+    async fn land(&self, flight: &str, id: u64, output: JobOutput, outcome: Outcome) {
+        let followers = self.inner.flights.lock().await.remove(flight).unwrap_or_default();
+        for id in std::iter::once(id).chain(followers) {
+            match &outcome {
+                Ok((message_code, message)) => self.done_with(id, output.clone(), message_code, message).await,
+                Err(e) => self.fail(id, e.clone()).await,
+            }
+        }
+    }
+
+    // Same as 'report_blocking', but for all jobs of the flight.
+    fn report_flight_blocking(&self, flight: &str, id: u64, phase: Phase) {
+        let followers = self
+            .inner
+            .flights
+            .blocking_lock()
+            .get(flight)
+            .cloned()
+            .unwrap_or_default();
+        for id in std::iter::once(id).chain(followers) {
+            self.report_blocking(id, phase);
+        }
+    }
+
+    async fn count(&self, id: u64, request: &JobRequest, key: &str, hash: String, flight: &str) -> Outcome {
+        let db = &self.inner.db;
+
+        let cached = {
+            // Note(andrew): Here we are looking for our repository in the cache, and if it is present,
+            //     we check if it is still relevant. Relevancy is determined by checking if the latest
+            //     hash at the target ref of the repository macthes stored hash. If it does not match, we know
+            //     that new commits have been pushed to the repository since our last analysis, so there
+            //     is some chance that stored result is inaccurate.
+            data::preload(db, Kind::Data, &[key]).await;
+            let mut guard = db.lock().await; // It is important for us that this lock will be freed after the code block.
+
+            match guard.get(key) {
+                Some(data) if data.hash == hash => {
+                    let msg = "Your request was satisfied instantly, because it was found in cache.";
+                    Some(("info_success_cached", msg.to_string()))
+                }
+                // Note(andrew): Repository has changed, but big ones are only reanalyzed once in a while (see
+                //     'Freshness'), so the result of the older commit is served until then.
+                Some(data) => self
+                    .outdated_message(data.size_bytes, data.creation_time)
+                    .map(|msg| ("info_success_cached_outdated", msg)),
+                None => None,
+            }
+        };
+
+        if let Some((code, msg)) = cached {
+            // Note(andrew): Before finishing, we need to update cached data with current time, so
+            //     we will be able to tell on the next request with the same cached target whether
+            //     we updated it recently enough and can respond immediately.
+            data::verify(db, key, now()).await;
+            return Ok((code, msg));
+        }
+
+        // Note(andrew): The analysis itself is synchronous, so it is moved into the blocking thread pool,
+        //     and reports its progress back into the jobs from there.
+        let queue = self.clone();
+        let (request, flight) = (request.clone(), flight.to_string());
+        let result = task::spawn_blocking(move || {
+            let JobRequest {
+                username,
+                reponame,
+                repo_url,
+                target,
+                options,
+                env,
+                ..
+            } = request;
+            let limits = &queue.inner.limits;
+            get_data_from_repo(username, reponame, repo_url, target, &options, &env, limits, |phase| {
+                queue.report_flight_blocking(&flight, id, phase)
+            })
+        })
+        .await;
+
+        let mut data = match result {
+            Ok(Ok(data)) => data,
+            Ok(Err(e)) => return Err(e),
+            Err(e) => {
+                let msg = format!("Internal error while analyzing repository: {}", e);
+                return Err(KloccError::Internal(msg));
+            }
+        };
+
+        data.hash = hash;
+        data.private = request.credential.is_some();
+        data::store(db, key.to_string(), data).await;
+
+        // Tracking repository statistics.
+        TOTAL_REPOSITORIES_SERVED.inc();
+
+        let msg = "The repo was analyzed successfully and result was stored for later reference.";
+        Ok(("info_success_generated", msg.to_string()))
+    }
+
+    // Explain why the cached result of the older commit is served, if it is too early to reanalyze it.
+    fn outdated_message(&self, size_bytes: u64, creation_time: u64) -> Option<String> {
+        let interval = self.freshness(size_bytes).reanalyze_interval;
+        let next = creation_time + interval;
+        match next > now() {
+            true => Some(format!(
+                "Repository has new commits, but repositories of this size ({:.1} MB) are reanalyzed at most every \
+                 {} minutes, so this result is from the older commit (next analysis is possible in {} minutes).",
+                (size_bytes as f64) / (1024.0 * 1024.0),
+                interval / 60,
+                (next - now()).div_ceil(60)
+            )),
+            false => None,
+        }
+    }
+
+    // This method will return a hash of the latest commit in the repository for us to save for later,
+    // or an error if the repository doesn't exist (or it's not available).
+    async fn latest_hash(&self, request: &JobRequest) -> Result<String, KloccError> {
+        let (repo_url, target, env) = (request.repo_url.clone(), request.target.clone(), request.env.clone());
+        match task::spawn_blocking(move || get_latest_hash(repo_url, target, &env)).await {
+            Ok(result) => result,
+            Err(e) => Err(KloccError::Internal(format!(
+                "Internal error while fetching latest hash: {}",
+                e
+            ))),
+        }
+    }
+
+    // Same as 'process', but for the commit activity, which is stored separately (see 'Storage').
+    async fn process_activity(&self, id: u64, request: JobRequest) {
+        let key = request.key();
+
+        let hash = match self.latest_hash(&request).await {
+            Ok(value) => value,
+            Err(e) => return self.fail(id, e).await,
+        };
+
+        let flight = format!("activity {}@{}", key, hash);
+        if !self.take_off(&flight, id).await {
+            return; // Early return, the job is finished along with the one it has joined (see 'land').
+        }
+        let outcome = self.activity(id, &request, &key, hash, &flight).await;
+        self.land(&flight, id, JobOutput::Activity, outcome).await;
+    }
+
+    async fn activity(&self, id: u64, request: &JobRequest, key: &str, hash: String, flight: &str) -> Outcome {
+        let db = &self.inner.db;
+
+        let cached = {
+            data::preload(db, Kind::Activity, &[key]).await;
+            let mut guard = db.lock().await;
+            match guard.get_activity(key) {
+                Some(activity) if activity.hash == hash => {
+                    let msg = "Your request was satisfied instantly, because it was found in cache.";
+                    Some((activity.clone(), "info_success_cached", msg.to_string()))
+                }
+                // Same as for the line counts, see 'count'.
+                Some(activity) => self
+                    .outdated_message(activity.size_bytes, activity.creation_time)
+                    .map(|msg| (activity.clone(), "info_success_cached_outdated", msg)),
+                None => None,
+            }
+        };
+
+        if let Some((mut activity, code, msg)) = cached {
+            activity.verified_time = now();
+            data::store_activity(db, key.to_string(), activity).await;
+            return Ok((code, msg));
+        }
+
+        let queue = self.clone();
+        let (request, flight) = (request.clone(), flight.to_string());
+        let result = task::spawn_blocking(move || {
+            get_activity_from_repo(
+                &request.reponame,
+                &request.repo_url,
+                &request.target,
+                &request.env,
+                &queue.inner.limits,
+                |phase| queue.report_flight_blocking(&flight, id, phase),
+            )
+        })
+        .await;
+
+        let mut activity = match result {
+            Ok(Ok(value)) => value,
+            Ok(Err(e)) => return Err(e),
+            Err(e) => {
+                let msg = format!("Internal error while analyzing repository history: {}", e);
+                return Err(KloccError::Internal(msg));
+            }
+        };
+
+        activity.hash = hash;
+        activity.private = request.credential.is_some();
+        data::store_activity(db, key.to_string(), activity).await;
+
+        let msg = "The repo history was analyzed successfully and result was stored for later reference.";
+        Ok(("info_success_generated", msg.to_string()))
+    }
+
+    async fn process_timeline(&self, id: u64, request: JobRequest, timeline: TimelineOptions) {
+        let queue = self.clone();
+        let result = task::spawn_blocking(move || queue.timeline_blocking(id, &request, &timeline)).await;
+
+        let (samples, counted) = match result {
+            Ok(Ok(value)) => value,
+            Ok(Err(e)) => return self.fail(id, e).await,
+            Err(e) => {
+                let msg = format!("Internal error while analyzing repository history: {}", e);
+                return self.fail(id, KloccError::Internal(msg)).await;
+            }
+        };
+
+        let msg = format!(
+            "The timeline was generated, {} of {} commits were analyzed, the rest were found in cache.",
+            counted,
+            samples.len()
+        );
+        self.done_with(id, JobOutput::Timeline(samples), "info_success_generated", &msg)
+            .await;
+    }
+
+    // Note(andrew): Each commit of the timeline is stored in the cache as a separate analysis result, under
+    //     the same key as if that commit was requested directly, so extending the timeline (or asking for
+    //     the commit later) only counts commits that were never counted before. Returns picked samples,
+    //     along with the amount of commits that were actually counted.
+    fn timeline_blocking(
+        &self,
+        id: u64,
+        request: &JobRequest,
+        timeline: &TimelineOptions,
+    ) -> Result<(Vec<Sample>, usize), KloccError> {
+        let db = &self.inner.db;
+        let (repo_url, credential) = (&request.repo_url, request.credential.as_deref());
+
+        self.report_blocking(id, Phase::Started);
+        self.report_blocking(id, Phase::Cloning);
+        let limits = &self.inner.limits;
+        let history = fetch_history(&request.reponame, repo_url, &request.target, &request.env, limits)?;
+
+        let mut samples = Vec::new();
+        let mut counted = 0;
+        for commit in timeline::pick(&history.commits, timeline) {
+            let key = cache_key(repo_url, &commit.hash, credential, &request.options);
+
+            data::preload_blocking(db, Kind::Data, &[&key]);
+            if db.blocking_lock().get(&key).is_none() {
+                self.report_blocking(id, Phase::Counting);
+                let mut data = history.count(
+                    &request.reponame,
+                    repo_url,
+                    &commit.hash,
+                    &request.options,
+                    &request.env,
+                )?;
+                data.hash = commit.hash.clone();
+                data.private = credential.is_some();
+                data::store_blocking(db, key.clone(), data);
+                counted += 1;
+            }
+
+            samples.push(Sample { commit, key });
+        }
+
+        self.report_blocking(id, Phase::CleaningUp);
+        history.close(repo_url);
+
+        Ok((samples, counted))
+    }
+
+    async fn process_diff(&self, id: u64, request: JobRequest, base: String) {
+        let queue = self.clone();
+        let result = task::spawn_blocking(move || {
+            let base = queue.analyze_blocking(id, &request, &base)?;
+            let head = queue.analyze_blocking(id, &request, &request.target)?;
+            Ok::<_, KloccError>((base, head))
+        })
+        .await;
+
+        let (base, head) = match result {
+            Ok(Ok(value)) => value,
+            Ok(Err(e)) => return self.fail(id, e).await,
+            Err(e) => {
+                let msg = format!("Internal error while comparing refs: {}", e);
+                return self.fail(id, KloccError::Internal(msg)).await;
+            }
+        };
+
+        let msg = "Both refs were analyzed successfully, and the results were compared.";
+        self.done_with(id, JobOutput::Diff(base, head), "info_success_generated", msg)
+            .await;
+    }
+
+    // Note(andrew): Analyze the given ref of the repository, unless its latest commit was analyzed already.
+    //     Result is stored under the key of the commit hash, instead of the ref, so it is shared with every
+    //     other diff (or timeline, or request of that commit) that ends up on the same commit.
+    fn analyze_blocking(&self, id: u64, request: &JobRequest, target: &str) -> Result<Side, KloccError> {
+        let db = &self.inner.db;
+        let credential = request.credential.as_deref();
+
+        let hash = get_latest_hash(request.repo_url.clone(), target.to_string(), &request.env)?;
+        let key = cache_key(&request.repo_url, &hash, credential, &request.options);
+
+        // Note(andrew): Resolved hash is fetched instead of the ref, so the analysis matches the cache key
+        //     even if the ref moved in the meantime. This is also the only way to get refs which are not
+        //     branches or tags (e.g. 'refs/pull/1/head'), since those can't be cloned directly.
+        data::preload_blocking(db, Kind::Data, &[&key]);
+        if db.blocking_lock().get(&key).is_none() {
+            let mut data = get_data_from_repo(
+                request.username.clone(),
+                request.reponame.clone(),
+                request.repo_url.clone(),
+                hash.clone(),
+                &request.options,
+                &request.env,
+                &self.inner.limits,
+                |phase| self.report_blocking(id, phase),
+            )?;
+            data.hash = hash.clone();
+            data.private = credential.is_some();
+            data::store_blocking(db, key.clone(), data);
+        }
+
+        Ok(Side {
+            target: target.to_string(),
+            hash,
+            key,
+        })
+    }
+}
@@ -0,0 +1,132 @@
+use rocket::serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::error::KloccError;
+
+const MB: u64 = 1024 * 1024;
+
+// How often the size of the repository is checked while git is running (see 'Watch::check').
+const USAGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// Note(andrew): Limits of a single analysis, which are protecting the server from the repositories that are
+//     too big for it (e.g. multi-GB monorepos filling the disk, or holding the worker forever). Git is killed
+//     as soon as any of them is exceeded, and the temporary directory is removed along with everything it
+//     has fetched so far. Zero is no limit.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde", default)]
+pub struct Limits {
+    // Wall time of fetching the repository (i.e. all git commands of the clone and checkout), in seconds.
+    pub clone_timeout: u64,
+    // Size of the repository on disk in megabytes, including git metadata.
+    pub max_size_mb: u64,
+    // Number of files of the repository, including git metadata.
+    pub max_files: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            clone_timeout: 60 * 10,
+            max_size_mb: 4096,
+            max_files: 500_000,
+        }
+    }
+}
+
+impl Limits {
+    // No limits at all, for the command line mode (see 'cli'), where the only one we could hurt is the caller.
+    pub fn unlimited() -> Self {
+        Self {
+            clone_timeout: 0,
+            max_size_mb: 0,
+            max_files: 0,
+        }
+    }
+
+    // Start watching the repository at the given path, where the clock of the 'clone_timeout' starts now.
+    pub fn watch(&self, path: &str) -> Watch {
+        Watch {
+            limits: self.clone(),
+            path: PathBuf::from(path),
+            deadline: match self.clone_timeout {
+                0 => None,
+                seconds => Some(Instant::now() + Duration::from_secs(seconds)),
+            },
+            checked: None,
+        }
+    }
+}
+
+// Size of the directory on disk in bytes, along with the number of files in it, including git metadata. Symlinks
+// are not followed, and anything we fail to read is not counted, since this is only an estimate.
+pub fn dir_usage(path: &Path) -> (u64, u64) {
+    let entries = match fs::read_dir(path) {
+        Ok(value) => value,
+        Err(_) => return (0, 0),
+    };
+
+    entries
+        .flatten()
+        .map(|entry| match entry.file_type() {
+            Ok(kind) if kind.is_dir() => dir_usage(&entry.path()),
+            Ok(kind) if kind.is_file() => (entry.metadata().map_or(0, |meta| meta.len()), 1),
+            _ => (0, 0),
+        })
+        .fold((0, 0), |(size, files), (s, f)| (size + s, files + f))
+}
+
+// Limits of the analysis of the repository at the given path, which are checked while git is running.
+pub struct Watch {
+    limits: Limits,
+    path: PathBuf,
+    deadline: Option<Instant>,
+    // When the size of the repository was checked the last time.
+    checked: Option<Instant>,
+}
+
+impl Watch {
+    // Check the limits while git is running. Size of the repository is only checked once in a while, since
+    // it is a walk over the whole directory.  @Speed
+    pub fn check(&mut self) -> Result<(), KloccError> {
+        if self.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(KloccError::CloneTimeout(format!(
+                "Fetching the repository took longer than {} seconds, which is the limit of this server.",
+                self.limits.clone_timeout
+            ))); // Early return.
+        }
+
+        if self
+            .checked
+            .is_some_and(|checked| checked.elapsed() < USAGE_CHECK_INTERVAL)
+        {
+            return Ok(()); // Early return.
+        }
+        self.checked = Some(Instant::now());
+        self.check_usage()
+    }
+
+    // Check the size of the repository right away (e.g. once git is done).
+    pub fn check_usage(&self) -> Result<(), KloccError> {
+        let (max_size_mb, max_files) = (self.limits.max_size_mb, self.limits.max_files);
+        if max_size_mb == 0 && max_files == 0 {
+            return Ok(()); // Early return, nothing to walk over.
+        }
+
+        let (size, files) = dir_usage(&self.path);
+        if max_size_mb > 0 && size > max_size_mb.saturating_mul(MB) {
+            return Err(KloccError::RepoTooLarge(format!(
+                "Repository is bigger than {} MB, which is the limit of this server.",
+                max_size_mb
+            )));
+        }
+        if max_files > 0 && files > max_files {
+            return Err(KloccError::RepoTooLarge(format!(
+                "Repository has more than {} files, which is the limit of this server.",
+                max_files
+            )));
+        }
+        Ok(())
+    }
+}
@@ -1,18 +1,60 @@
 #[macro_use]
 extern crate rocket;
 use prometheus::TextEncoder;
+use rocket::fairing::AdHoc;
 use rocket::http::Method;
+use rocket::{Build, Rocket};
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
+use std::sync::Arc;
 
+// Note(andrew): Utils go first, since macros defined there are only visible to modules declared after.
+#[macro_use]
+mod utils;
+
+mod activity;
+mod authors;
+mod badge;
 mod body;
+mod cli;
+mod config;
 mod counter;
+mod credentials;
 mod data;
+mod diff;
 mod endpoints;
+mod error;
+mod exclude;
+mod formats;
+mod freshness;
+mod jobs;
+mod limits;
 mod prom;
-mod utils;
+mod providers;
+mod query;
+mod response;
+mod storage;
+mod timeline;
+mod tree;
+
+// Note(andrew): Same binary is both the server and the command line tool (see 'cli'), where running it
+//     without arguments is the server, so existing deployments keep working as they are.
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match cli::parse(&args) {
+        // Same as what '#[launch]' does, where launch error is reported by rocket itself (on drop).
+        Ok(cli::Command::Serve) => {
+            let _ = rocket::execute(rocket().launch());
+        }
+        Ok(cli::Command::Count(args)) => std::process::exit(cli::count(args)),
+        Ok(cli::Command::Help) => print!("{}", cli::USAGE),
+        Err(e) => {
+            eprint!("{}\n\n{}", e, cli::USAGE);
+            std::process::exit(2);
+        }
+    }
+}
 
-#[launch]
-fn rocket() -> _ {
+fn rocket() -> Rocket<Build> {
     // Note(andrew): Here we use additional rocket crate to handle browser configurations for us, because
     //     without this, browsers refuse to call into our API, because they lack headers (not that hard to
     //     add), and also lack handlers for OPTIONS with proper response, which is one of the main reasons
@@ -80,14 +122,51 @@ fn rocket() -> _ {
     // gatherer and encoder, as opposed to not shown until first increment).
     prom::TOTAL_REQUESTS_SERVED.reset();
     prom::TOTAL_REPOSITORIES_SERVED.reset();
+    prom::COALESCED_REQUESTS.reset();
+    for kind in ["data", "activity"] {
+        prom::CACHE_HITS.with_label_values(&[kind]).reset();
+        prom::CACHE_MISSES.with_label_values(&[kind]).reset();
+        prom::CACHE_EVICTIONS.with_label_values(&[kind]).reset();
+        prom::CACHE_RELOADS.with_label_values(&[kind]).reset();
+    }
+
+    let rocket = rocket::build();
+    let config = config::load(rocket.figment());
+
+    // Note(andrew): Cache is shared between the endpoints and the job workers, which are running on
+    //     their own outside of any request, hence the 'Arc'.
+    let db = Arc::new(data::init_db(&config));
+    let freshness = freshness::Freshness::new(&config.freshness);
+    let queue = jobs::JobQueue::new(db.clone(), freshness, config.limits.clone(), config.queue_size);
 
-    rocket::build()
+    rocket
         // Register our endpoints with /api/ root prefix.
-        .mount("/api", routes![endpoints::post_klocc_job, endpoints::get_health,])
+        .mount(
+            "/api",
+            routes![
+                endpoints::post_klocc_job,
+                endpoints::get_klocc_job,
+                endpoints::get_klocc_job_events,
+                endpoints::post_klocc_diff,
+                endpoints::post_klocc_activity,
+                endpoints::get_klocc_repo,
+                endpoints::get_klocc_badge,
+                endpoints::get_health,
+            ],
+        )
         .mount("/", routes![endpoints::get_metrics,])
         // Managing cache mutex. This allows rocket to pass this instance to us in any handler where we need
         // it, using rocket's internal 'State' wrapper.
-        .manage(data::init_db())
+        .manage(db)
+        // Registry of git service providers, which we are allowed to clone from.
+        .manage(providers::Providers::new(&config.providers))
+        // Server-side credentials for private repositories, which callers can use with the access token.
+        .manage(credentials::Credentials::new(&config.credentials))
+        // Job queue, and background workers, which are started as soon as server is up and running.
+        .manage(queue.clone())
+        .attach(AdHoc::on_liftoff("Job workers", move |_| {
+            Box::pin(async move { queue.start(config.workers) })
+        }))
         // Adding CORS middleware.
         .attach(cors)
         // Encoder for the prometheus metadata.
@@ -1,5 +1,8 @@
 use lazy_static::lazy_static;
-use prometheus::{HistogramVec, IntCounter, register_histogram_vec, register_int_counter};
+use prometheus::{
+    HistogramVec, IntCounter, IntCounterVec, IntGauge, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge,
+};
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::http::Method;
 use rocket::{Data, Request, Response};
@@ -19,7 +22,7 @@ lazy_static! {
     pub static ref JOB_REQUESTS_DURATION: HistogramVec = register_histogram_vec!(
         "klocc_jobs_requests_duration_seconds",
         "Jobs endpoint latencies in seconds",
-        &["handler"]
+        &["handler", "status"]
     )
     .unwrap();
     pub static ref JOB_RESPONSE_SIZE_BYTES: HistogramVec = register_histogram_vec!(
@@ -29,6 +32,47 @@ lazy_static! {
         vec![256., 1024., 4096., 16384., 65536., 262144., 1048576., 4194304.],
     )
     .unwrap();
+    pub static ref ACTIVE_JOBS: IntGauge =
+        register_int_gauge!("klocc_active_jobs", "Number of jobs being processed by the workers").unwrap();
+    pub static ref QUEUED_JOBS: IntGauge =
+        register_int_gauge!("klocc_queued_jobs", "Number of jobs waiting for the workers").unwrap();
+    pub static ref COALESCED_REQUESTS: IntCounter = register_int_counter!(
+        "klocc_coalesced_requests_total",
+        "Total number of jobs that joined the analysis of the same commit already in progress"
+    )
+    .unwrap();
+    // Note(andrew): Cache metrics are labeled by the kind of the entry (i.e. 'data' or 'activity', see
+    //     'Storage'). Miss is any read of the entry that is not in memory, even when it is read back from
+    //     the disk afterwards (which is counted as reload as well).
+    pub static ref CACHE_HITS: IntCounterVec = register_int_counter_vec!(
+        "klocc_cache_hits_total",
+        "Total number of cache reads of the entries kept in memory",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref CACHE_MISSES: IntCounterVec = register_int_counter_vec!(
+        "klocc_cache_misses_total",
+        "Total number of cache reads of the entries missing in memory",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref CACHE_EVICTIONS: IntCounterVec = register_int_counter_vec!(
+        "klocc_cache_evictions_total",
+        "Total number of cache entries evicted from memory to stay within the memory budget",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref CACHE_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "klocc_cache_reloads_total",
+        "Total number of evicted cache entries read back from the disk",
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref CACHE_SIZE_BYTES: IntGauge = register_int_gauge!(
+        "klocc_cache_size_bytes",
+        "Approximate amount of memory taken by the cache entries"
+    )
+    .unwrap();
 }
 
 pub struct PrometheusCollection;
@@ -60,10 +104,13 @@ impl Fairing for PrometheusCollection {
             let duration_timer = request.local_cache(|| DurationTimer(None));
             if let Some(duration) = duration_timer.0.map(|st| st.elapsed()) {
                 let latency_ms = duration.as_millis();
+                // Note(andrew): Labeling by status class (e.g. '2xx', '4xx'), and not by exact status code,
+                //     to keep the amount of label combinations (i.e. time series) small.
+                let status_class = format!("{}xx", response.status().code / 100);
 
                 JOB_REQUESTS_DURATION
                     .local()
-                    .with_label_values(&["all"])
+                    .with_label_values(&["all", &status_class])
                     .observe(latency_ms as f64 / 1000.);
                 // While we can, lets add response header with timing as well.
                 response.set_raw_header("X-Response-Time", format!("{} ms", latency_ms));
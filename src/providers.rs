@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use crate::error::KloccError;
+
+// Note(andrew): Providers that are always available, even without any configuration. Each provider
+//     is a template of the repository url, where '{username}' and '{reponame}' are substituted with
+//     values from the request. Operators can add their own (e.g. self-hosted GitLab or Forgejo) or
+//     override these in the 'providers' table of the config (see 'Config').
+const BUILTIN_PROVIDERS: &[(&str, &str)] = &[
+    ("github", "https://github.com/{username}/{reponame}.git"),
+    ("gitlab", "https://gitlab.com/{username}/{reponame}.git"),
+    ("bitbucket", "https://bitbucket.org/{username}/{reponame}.git"),
+    ("codeberg", "https://codeberg.org/{username}/{reponame}.git"),
+    ("sourcehut", "https://git.sr.ht/~{username}/{reponame}"),
+];
+
+// Registry of the known git service providers, mapping provider name to the url template.
+pub struct Providers {
+    templates: HashMap<String, String>,
+}
+
+// Check that given part of the url (i.e. username or reponame) can't be used to escape the template,
+// and point us to some other host or path. Slashes are only allowed when 'nested' is set, since some
+// providers (e.g. GitLab subgroups) have nested namespaces.
+fn validate_segment(kind: &str, value: &str, nested: bool) -> Result<(), KloccError> {
+    let valid_chars = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+
+    let parts: Vec<&str> = match nested {
+        true => value.split('/').collect(),
+        false => vec![value],
+    };
+    for part in parts {
+        // Note(andrew): Besides characters themselves, we are not allowing '.' and '..' to avoid path
+        //     traversal, and leading '-' so the value can't be confused with an option by 'git'.
+        if part.is_empty() || part == "." || part == ".." || part.starts_with('-') || !part.chars().all(valid_chars) {
+            return Err(KloccError::InvalidRepository(format!(
+                "Value '{}' is not a valid {}!",
+                value, kind
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+impl Providers {
+    // Create registry from the built-in providers, extended (or overridden) by configured ones.
+    pub fn new(configured: &HashMap<String, String>) -> Self {
+        let mut templates: HashMap<String, String> = BUILTIN_PROVIDERS
+            .iter()
+            .map(|(name, template)| (name.to_string(), template.to_string()))
+            .collect();
+        templates.extend(configured.clone());
+
+        Self { templates }
+    }
+
+    // This function takes service name, and other useful arguments, and expands them into
+    // a valid url for git repository for specific service provider. In theory, we could
+    // just allow passing url, but it's questionable decision from the security standpoint,
+    // which is why only operator can configure hosts that are allowed (see 'Providers::new').
+    pub fn expand_url(&self, service: &str, username: &str, reponame: &str) -> Result<String, KloccError> {
+        let template = match self.templates.get(service) {
+            Some(value) => value,
+            None => {
+                let mut names: Vec<&str> = self.templates.keys().map(String::as_str).collect();
+                names.sort();
+                return Err(KloccError::UnsupportedProvider(format!(
+                    "Service provider for git with a name '{}' is not supported! Supported providers: {}.",
+                    service,
+                    names.join(", ")
+                )));
+            }
+        };
+
+        validate_segment("username", username, true)?;
+        validate_segment("reponame", reponame, false)?;
+
+        Ok(template.replace("{username}", username).replace("{reponame}", reponame))
+    }
+}
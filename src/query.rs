@@ -0,0 +1,233 @@
+use rocket::serde::json::{Value, json};
+use std::cmp::Reverse;
+
+use crate::data::{Data, FileInfo, Info};
+use crate::error::KloccError;
+
+// Amount of files per page, when the callee didn't ask for a specific one, and the most we allow.
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10000;
+
+// Query of the 'GET /api/repos/...' endpoint, as it comes from the url. Everything is optional, and
+// numbers are parsed by hand (see 'validate'), since rocket silently drops values it fails to parse.
+#[derive(FromForm, Default, Debug)]
+pub struct RepoQuery {
+    #[field(name = "ref")]
+    pub target: Option<String>,
+    // Comma-separated language names (case-insensitive), e.g. 'Rust,Python'.
+    pub languages: Option<String>,
+    pub path_prefix: Option<String>,
+    pub top_files: Option<String>,
+    // Comma-separated parts of the result: 'summary', 'languages' and 'files' (all of them by default).
+    pub fields: Option<String>,
+    pub cursor: Option<String>,
+    pub limit: Option<String>,
+}
+
+// Validated version of the 'RepoQuery'.
+#[derive(Debug)]
+pub struct View {
+    languages: Option<Vec<String>>,
+    path_prefix: Option<String>,
+    top_files: Option<usize>,
+    summary: bool,
+    with_languages: bool,
+    with_files: bool,
+    cursor: Option<Cursor>,
+    limit: usize,
+}
+
+// Note(andrew): Cursor is the offset of the next page in the sorted list of files, along with the commit it
+//     was made for, so we don't silently mix pages of different commits, when the entry was updated in
+//     between. It is opaque to the callee, who is only supposed to pass it back as is.
+#[derive(Debug)]
+struct Cursor {
+    offset: usize,
+    hash: String,
+}
+
+impl Cursor {
+    fn new(offset: usize, hash: &str) -> Self {
+        Self {
+            offset,
+            hash: hash.chars().take(12).collect(),
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let (offset, hash) = value.split_once('.')?;
+        Some(Self {
+            offset: offset.parse().ok()?,
+            hash: hash.to_string(),
+        })
+    }
+
+    fn to_value(&self) -> String {
+        format!("{}.{}", self.offset, self.hash)
+    }
+}
+
+fn parse_number(name: &str, value: &Option<String>) -> Result<Option<usize>, KloccError> {
+    match value {
+        Some(string) => match string.parse::<usize>() {
+            Ok(number) => Ok(Some(number)),
+            Err(_) => Err(KloccError::InvalidOptions(format!(
+                "Value of '{}' must be a non-negative number, got '{}'.",
+                name, string
+            ))),
+        },
+        None => Ok(None),
+    }
+}
+
+impl RepoQuery {
+    pub fn validate(&self) -> Result<View, KloccError> {
+        let (mut summary, mut with_languages, mut with_files) = (true, true, true);
+        if let Some(fields) = &self.fields {
+            (summary, with_languages, with_files) = (false, false, false);
+            for field in fields.split(',').map(str::trim) {
+                match field {
+                    "summary" => summary = true,
+                    "languages" => with_languages = true,
+                    "files" => with_files = true,
+                    _ => {
+                        let msg = format!("Unknown field '{}', expected 'summary', 'languages' or 'files'.", field);
+                        return Err(KloccError::InvalidOptions(msg));
+                    }
+                }
+            }
+        }
+
+        let cursor = match &self.cursor {
+            Some(value) => match Cursor::parse(value) {
+                Some(cursor) => Some(cursor),
+                None => return Err(KloccError::InvalidOptions(format!("Invalid cursor '{}'.", value))),
+            },
+            None => None,
+        };
+
+        let limit = parse_number("limit", &self.limit)?.unwrap_or(DEFAULT_LIMIT);
+        if limit == 0 || limit > MAX_LIMIT {
+            let msg = format!("Value of 'limit' must be between 1 and {}, got {}.", MAX_LIMIT, limit);
+            return Err(KloccError::InvalidOptions(msg));
+        }
+
+        Ok(View {
+            languages: self.languages.as_ref().map(|value| {
+                value
+                    .split(',')
+                    .map(|name| name.trim().to_lowercase())
+                    .filter(|name| !name.is_empty())
+                    .collect()
+            }),
+            path_prefix: self.path_prefix.clone().filter(|prefix| !prefix.is_empty()),
+            top_files: parse_number("top_files", &self.top_files)?,
+            summary,
+            with_languages,
+            with_files,
+            cursor,
+            limit,
+        })
+    }
+}
+
+fn lines(file: &FileInfo) -> u64 {
+    (file.code as u64) + (file.comments as u64) + (file.blanks as u64)
+}
+
+// Note(andrew): Build the requested part of the cached result. When the result is filtered by languages or
+//     by path prefix, totals are recomputed from the files that are left (which means embedded code is not
+//     included in them, see 'LanguageInfo::children'). Files are a flat list across all languages, from the
+//     biggest to the smallest, which is what pagination goes over.
+pub fn render(data: &Data, view: &View) -> Result<Value, KloccError> {
+    if let Some(cursor) = &view.cursor
+        && !data.hash.starts_with(&cursor.hash)
+    {
+        let msg = "Cursor belongs to the older analysis result of this repository, start from the first page.";
+        return Err(KloccError::InvalidOptions(msg.to_string()));
+    }
+
+    let filtered = view.languages.is_some() || view.path_prefix.is_some();
+    let selected = data.languages.iter().filter(|lang| match &view.languages {
+        Some(names) => names.contains(&lang.name.to_lowercase()),
+        None => true,
+    });
+    let matches = |file: &FileInfo| match &view.path_prefix {
+        Some(prefix) => file.path.starts_with(prefix.as_str()),
+        None => true,
+    };
+
+    let mut total = Info::default();
+    let mut languages: Vec<Value> = Vec::new();
+    let mut files: Vec<(&str, &FileInfo)> = Vec::new();
+    for lang in selected {
+        let lang_files: Vec<&FileInfo> = lang.files.iter().filter(|file| matches(file)).collect();
+        if filtered && lang_files.is_empty() {
+            continue; // Nothing of this language is left after filtering.
+        }
+
+        let mut lang_total = Info::new(lang.total.code, lang.total.comments, lang.total.blanks);
+        if view.path_prefix.is_some() {
+            lang_total = Info::default();
+            for file in lang_files.iter() {
+                lang_total.code += file.code;
+                lang_total.comments += file.comments;
+                lang_total.blanks += file.blanks;
+            }
+        }
+        total.code += lang_total.code;
+        total.comments += lang_total.comments;
+        total.blanks += lang_total.blanks;
+
+        languages.push(json!({"name": lang.name, "total": lang_total, "files": lang_files.len()}));
+        files.extend(lang_files.into_iter().map(|file| (lang.name.as_str(), file)));
+    }
+
+    let mut result = json!({});
+    if view.summary {
+        result = json!({
+            "creation_time": data.creation_time, "verified_time": data.verified_time,
+            "repo": data.repo, "hash": data.hash, "branch": data.branch, "private": data.private,
+            "size_bytes": data.size_bytes,
+            "options": data.options, "embedded": data.embedded, "excluded": data.excluded,
+            "authorship": data.authorship,
+            "total": if filtered { json!(total) } else { json!(data.total) },
+        });
+    }
+    if view.with_languages {
+        result["languages"] = json!(languages);
+    }
+    if view.with_files {
+        files.sort_by(|(_, a), (_, b)| {
+            Reverse(lines(a))
+                .cmp(&Reverse(lines(b)))
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        if let Some(top) = view.top_files {
+            files.truncate(top);
+        }
+
+        let offset = view.cursor.as_ref().map_or(0, |cursor| cursor.offset);
+        let page: Vec<Value> = files
+            .iter()
+            .skip(offset)
+            .take(view.limit)
+            .map(|(language, file)| {
+                json!({
+                    "language": language, "name": file.name, "path": file.path,
+                    "code": file.code, "comments": file.comments, "blanks": file.blanks,
+                })
+            })
+            .collect();
+
+        let next = offset + view.limit;
+        result["files"] = json!(page);
+        result["files_total"] = json!(files.len());
+        result["next_cursor"] = match next < files.len() {
+            true => json!(Cursor::new(next, &data.hash).to_value()),
+            false => Value::Null,
+        };
+    }
+
+    Ok(result)
+}
@@ -0,0 +1,85 @@
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Value;
+
+// Note(andrew): Every endpoint of ours responds with the same json envelope:
+//
+//         {"status": 404, "message_code": "err_...", "message": "...", "data": ...}
+//
+//     Rocket sends any 'Value' with HTTP 200 though, which confuses monitoring and retry middlewares
+//     (and anyone else, who doesn't read the body). This wrapper takes HTTP status from the 'status'
+//     field of the envelope, so both of them are always the same, and we don't need to repeat status
+//     code in every handler. Same goes for the 'Retry-After' header, which is taken from the 'retry_after'
+//     field of the envelope, when it is present (see 'KloccError::retry_after').
+pub struct ApiResponse(pub Value);
+
+impl From<Value> for ApiResponse {
+    fn from(value: Value) -> Self {
+        ApiResponse(value)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        // Note(andrew): Envelope without a valid status is our bug, and not a reason to fail the request,
+        //     so we fall back to 500, which is what it is anyway.
+        let status = self.0["status"]
+            .as_u64()
+            .and_then(|code| u16::try_from(code).ok())
+            .and_then(Status::from_code)
+            .unwrap_or(Status::InternalServerError);
+
+        let retry_after = self.0["retry_after"].as_u64();
+
+        let mut response = self.0.respond_to(req)?;
+        response.set_status(status);
+        if let Some(seconds) = retry_after {
+            response.set_header(Header::new("Retry-After", seconds.to_string()));
+        }
+        Ok(response)
+    }
+}
+
+// Response of the endpoints, which can render the analysis result in other formats (see 'Format'), where
+// the envelope is still used for everything else (e.g. errors).
+pub enum FormattedResponse {
+    Envelope(ApiResponse),
+    Formatted(ContentType, String),
+}
+
+impl From<Value> for FormattedResponse {
+    fn from(value: Value) -> Self {
+        FormattedResponse::Envelope(ApiResponse(value))
+    }
+}
+
+impl<'r> Responder<'r, 'static> for FormattedResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            FormattedResponse::Envelope(response) => response.respond_to(req),
+            FormattedResponse::Formatted(content_type, body) => (content_type, body).respond_to(req),
+        }
+    }
+}
+
+// SVG badge along with the caching policy for it, since badges are mostly served through CDNs (e.g. GitHub
+// proxies every image of the README), which we want to hold onto them for as long as they are valid.
+pub struct BadgeResponse {
+    pub svg: String,
+    // Seconds the badge can be cached for, where 'None' means it shouldn't be cached at all.
+    pub max_age: Option<u64>,
+}
+
+impl<'r> Responder<'r, 'static> for BadgeResponse {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let cache_control = match self.max_age {
+            Some(seconds) => format!("public, max-age={}", seconds),
+            None => "no-cache, no-store, must-revalidate".to_string(),
+        };
+
+        let mut response = (ContentType::SVG, self.svg).respond_to(req)?;
+        response.set_header(Header::new("Cache-Control", cache_control));
+        Ok(response)
+    }
+}
@@ -0,0 +1,651 @@
+use rocket::serde::json::{from_str, to_string};
+use rocket::serde::{Deserialize, DeserializeOwned, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::activity::Activity;
+use crate::data::Data;
+use crate::prom::{CACHE_EVICTIONS, CACHE_HITS, CACHE_MISSES, CACHE_RELOADS, CACHE_SIZE_BYTES};
+
+// Note(andrew): Interface of the cache storage, where analysis results are kept between requests. The
+//     storage itself is not synchronized, since it is always wrapped into a mutex (see 'Database').
+//     Keep in mind that all calls are made while holding that lock, so implementations should avoid
+//     doing anything slow in here (e.g. entries evicted from memory are read back outside of the lock,
+//     see 'data::preload', and new entries are serialized and written outside of it, see 'data::store').
+//
+//     Besides the line counts ('Data'), storage also keeps commit activity reports ('Activity'), which are
+//     a separate kind of analysis, stored under the same keys, but separately from the line counts.
+//
+//     Reads are taking mutable reference, since every read counts as a use of the entry (see 'Lru').
+pub trait Storage: Send {
+    fn get(&mut self, key: &str) -> Option<&Data>;
+    // Same as 'get', but only for the entry in memory, and without counting it as a use.
+    fn peek(&self, key: &str) -> Option<&Data>;
+    // Keep the entry of the given size (see 'prepare') in memory, it is already on the disk (if any).
+    fn insert(&mut self, key: String, data: Data, size: u64);
+    // Update time of the last verification (see 'Freshness') of the existing entry in memory.
+    fn set_verified_time(&mut self, key: &str, time: u64);
+    // Where the entries are persisted, if anywhere (see 'Disk').
+    fn disk(&self) -> Option<Disk>;
+    // Entry of the given kind, which is not in memory, but might be kept elsewhere (see 'Evicted').
+    fn evicted(&self, kind: Kind, key: &str) -> Option<Evicted>;
+    // Bring the entry read by 'Evicted::read' back into memory, unless it is already there.
+    fn restore(&mut self, entry: Restored);
+    // Amount of line count results kept in memory.
+    fn len(&self) -> usize;
+    // Approximate amount of memory taken by all entries kept in memory (see 'Lru').
+    fn size_bytes(&self) -> u64;
+
+    fn get_activity(&mut self, key: &str) -> Option<&Activity>;
+    fn insert_activity(&mut self, key: String, activity: Activity, size: u64);
+}
+
+// Kind of the analysis result kept in the storage.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Kind {
+    Data,
+    Activity,
+}
+
+impl Kind {
+    // Label of the cache metrics (see 'prom').
+    fn label(&self) -> &'static str {
+        match self {
+            Kind::Data => "data",
+            Kind::Activity => "activity",
+        }
+    }
+}
+
+enum Cached {
+    Data(Data),
+    Activity(Activity),
+}
+
+impl Cached {
+    fn kind(&self) -> Kind {
+        match self {
+            Cached::Data(_) => Kind::Data,
+            Cached::Activity(_) => Kind::Activity,
+        }
+    }
+}
+
+type Key = (Kind, String);
+
+struct Slot {
+    value: Cached,
+    size: u64,
+    // Value of the 'Lru::tick' at the last use of the entry.
+    used: u64,
+}
+
+// Note(andrew): Entries kept in memory, where the least recently used ones are evicted as soon as all of
+//     them together go over the memory budget (see 'Config::cache_budget_mb'). Line counts and activity
+//     reports are sharing the same budget. Size of the entry is the length of its json, which is not the
+//     exact amount of memory it takes, but it is proportional to it, and it is cheap enough to get, since
+//     we only do it once per analysis. The entry that was just inserted is never evicted, even if it
+//     doesn't fit into the budget on its own, since the job that produced it is about to read it.
+struct Lru {
+    // Zero is no budget at all, where nothing is ever evicted.
+    budget: u64,
+    entries: HashMap<Key, Slot>,
+    tick: u64,
+    bytes: u64,
+}
+
+impl Lru {
+    fn new(budget: u64) -> Self {
+        Self {
+            budget,
+            entries: HashMap::new(),
+            tick: 0,
+            bytes: 0,
+        }
+    }
+
+    // Mark the entry as used, returning whether it is in memory.
+    fn touch(&mut self, key: &Key) -> bool {
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(slot) => {
+                slot.used = self.tick;
+                CACHE_HITS.with_label_values(&[key.0.label()]).inc();
+                true
+            }
+            None => {
+                CACHE_MISSES.with_label_values(&[key.0.label()]).inc();
+                false
+            }
+        }
+    }
+
+    fn peek(&self, key: &Key) -> Option<&Cached> {
+        self.entries.get(key).map(|slot| &slot.value)
+    }
+
+    fn peek_mut(&mut self, key: &Key) -> Option<&mut Cached> {
+        self.entries.get_mut(key).map(|slot| &mut slot.value)
+    }
+
+    fn insert(&mut self, key: String, value: Cached, size: u64) {
+        self.tick += 1;
+        let key = (value.kind(), key);
+        let slot = Slot {
+            value,
+            size,
+            used: self.tick,
+        };
+        if let Some(old) = self.entries.insert(key.clone(), slot) {
+            self.bytes -= old.size;
+        }
+        self.bytes += size;
+
+        // Note(andrew): Looking for the least recently used entry is a scan over all of them, but it only
+        //     happens on inserts, which are rare (once per analysis), compared to reads.  @Speed
+        while self.budget > 0 && self.bytes > self.budget {
+            let oldest = self
+                .entries
+                .iter()
+                .filter(|(other, _)| **other != key)
+                .min_by_key(|(_, slot)| slot.used)
+                .map(|(other, _)| other.clone());
+            let oldest = match oldest {
+                Some(value) => value,
+                None => break, // Only the new entry is left.
+            };
+
+            // @SafeUnwrap: Key was just found in the map.
+            let slot = self.entries.remove(&oldest).unwrap();
+            self.bytes -= slot.size;
+            CACHE_EVICTIONS.with_label_values(&[oldest.0.label()]).inc();
+        }
+        CACHE_SIZE_BYTES.set(self.bytes as i64);
+    }
+
+    fn count(&self, kind: Kind) -> usize {
+        self.entries.keys().filter(|(other, _)| *other == kind).count()
+    }
+}
+
+fn size_of<T: Serialize>(value: &T) -> u64 {
+    to_string(value).map(|s| s.len() as u64).unwrap_or_default()
+}
+
+// Plain in-memory storage, which is gone on every restart (or eviction, see 'Lru').
+pub struct MemoryStorage {
+    lru: Lru,
+}
+
+impl MemoryStorage {
+    pub fn new(budget: u64) -> Self {
+        Self { lru: Lru::new(budget) }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, key: &str) -> Option<&Data> {
+        let key = (Kind::Data, key.to_string());
+        match self.lru.touch(&key) {
+            true => match self.lru.peek(&key) {
+                Some(Cached::Data(data)) => Some(data),
+                _ => None,
+            },
+            false => None,
+        }
+    }
+
+    fn peek(&self, key: &str) -> Option<&Data> {
+        match self.lru.peek(&(Kind::Data, key.to_string())) {
+            Some(Cached::Data(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: String, data: Data, size: u64) {
+        self.lru.insert(key, Cached::Data(data), size);
+    }
+
+    fn set_verified_time(&mut self, key: &str, time: u64) {
+        if let Some(Cached::Data(data)) = self.lru.peek_mut(&(Kind::Data, key.to_string())) {
+            data.verified_time = time;
+        }
+    }
+
+    fn disk(&self) -> Option<Disk> {
+        None
+    }
+
+    fn evicted(&self, _kind: Kind, _key: &str) -> Option<Evicted> {
+        None // Evicted entries are gone for good.
+    }
+
+    fn restore(&mut self, entry: Restored) {
+        restore(&mut self.lru, entry);
+    }
+
+    fn len(&self) -> usize {
+        self.lru.count(Kind::Data)
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.lru.bytes
+    }
+
+    fn get_activity(&mut self, key: &str) -> Option<&Activity> {
+        let key = (Kind::Activity, key.to_string());
+        match self.lru.touch(&key) {
+            true => match self.lru.peek(&key) {
+                Some(Cached::Activity(activity)) => Some(activity),
+                _ => None,
+            },
+            false => None,
+        }
+    }
+
+    fn insert_activity(&mut self, key: String, activity: Activity, size: u64) {
+        self.lru.insert(key, Cached::Activity(activity), size);
+    }
+}
+
+// Layout of a single file of the 'FileStorage'. Key is stored next to the data, since file name is
+// only a hash of the key.
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct EntryRef<'a, T> {
+    key: &'a str,
+    data: &'a T,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Entry<T> {
+    key: String,
+    data: T,
+}
+
+// Note(andrew): Keys are urls (plus ref), which are not valid file names, so we are naming files by a
+//     hash of the key. This has to be stable between builds and rust versions (as opposed to std hasher),
+//     hence the hand-written 64-bit FNV-1a.
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn entry_path(dir: &Path, key: &str) -> PathBuf {
+    dir.join(format!("{:016x}.json", fnv1a(key)))
+}
+
+// Note(andrew): Verification of the line counts (see 'Freshness') only changes the time, and it happens way
+//     more often than the analysis, so the time is kept in the small file next to the entry, instead of
+//     rewriting the whole entry every time. Newer of the two times wins when the entry is read.
+fn verified_path(entry: &Path) -> PathBuf {
+    entry.with_extension("verified")
+}
+
+fn read_verified(entry: &Path) -> Option<u64> {
+    fs::read_to_string(verified_path(entry)).ok()?.trim().parse().ok()
+}
+
+fn read_entry<T: DeserializeOwned>(path: &Path) -> Result<Entry<T>, String> {
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|s| from_str::<Entry<T>>(&s).map_err(|e| e.to_string()))
+}
+
+// Write given entry to the disk, returning its size (see 'Lru'). Errors are only logged, because the entry
+// is still in memory, and the worst outcome is that it will be missing after restart (or eviction).
+fn persist<T: Serialize>(dir: &Path, key: &str, data: &T) -> u64 {
+    let path = entry_path(dir, key);
+    let string = match to_string(&EntryRef { key, data }) {
+        Ok(value) => value,
+        Err(e) => {
+            info!("Failed to persist storage entry for {} at {:?}: {}", key, &path, e);
+            return 0;
+        }
+    };
+
+    // Note(andrew): Writing into temporary file first and then renaming it over the old one, so the
+    //     entry is replaced atomically, and we never end up with half-written file on crash. Name of the
+    //     temporary file is unique, since the same key might be written by two threads at once (see 'Disk').
+    let mut builder = tempfile::Builder::new();
+    builder.suffix(".tmp");
+    #[cfg(unix)]
+    builder.permissions(fs::Permissions::from_mode(0o666)); // Same as 'fs::write', instead of the private 0600.
+    let result = builder
+        .tempfile_in(dir)
+        .map_err(|e| e.to_string())
+        .and_then(|mut temp| {
+            temp.write_all(string.as_bytes())
+                .map(|_| temp)
+                .map_err(|e| e.to_string())
+        })
+        .and_then(|temp| temp.persist(&path).map(|_| ()).map_err(|e| e.to_string()));
+
+    if let Err(e) = result {
+        info!("Failed to persist storage entry for {} at {:?}: {}", key, &path, e);
+    }
+    string.len() as u64
+}
+
+// Directories of the 'FileStorage', which can be written to without holding the lock of the storage.
+#[derive(Clone)]
+pub struct Disk {
+    data_dir: PathBuf,
+    activity_dir: PathBuf,
+}
+
+impl Disk {
+    fn write<T: Serialize>(&self, kind: Kind, key: &str, value: &T) -> u64 {
+        match kind {
+            Kind::Data => persist(&self.data_dir, key, value),
+            Kind::Activity => persist(&self.activity_dir, key, value),
+        }
+    }
+
+    // Entry itself stays as it is on the disk, see 'verified_path'.
+    pub fn write_verified(&self, key: &str, time: u64) {
+        let path = verified_path(&entry_path(&self.data_dir, key));
+        if let Err(e) = fs::write(&path, time.to_string()) {
+            info!("Failed to persist verification time for {} at {:?}: {}", key, &path, e);
+        }
+    }
+}
+
+// Serialize the entry before it is inserted into the storage, writing it to the disk (if any), and returning
+// its size (see 'Lru'). This is slow for the big repositories, so it is done outside of the lock (see 'data::store').
+pub fn prepare<T: Serialize>(disk: Option<&Disk>, kind: Kind, key: &str, value: &T) -> u64 {
+    match disk {
+        Some(disk) => disk.write(kind, key, value),
+        None => size_of(value),
+    }
+}
+
+// Entry evicted from memory, which is still on the disk (see 'FileStorage'), and can be read from there
+// without holding the lock of the storage.
+pub struct Evicted {
+    kind: Kind,
+    key: String,
+    path: PathBuf,
+}
+
+impl Evicted {
+    // Read the entry from the disk, if it is there.
+    pub fn read(self) -> Option<Restored> {
+        let size = match fs::metadata(&self.path) {
+            Ok(meta) => meta.len(),
+            Err(_) => return None, // Early return, it was never stored.
+        };
+
+        match FileStorage::read(self.kind, &self.path) {
+            Ok((key, value)) if key == self.key => Some(Restored { key, value, size }),
+            // File of the other key with the same hash, which is going to be overwritten by this one.
+            Ok(_) => None,
+            Err(e) => {
+                info!("Failed to reload storage entry {:?}: {}", &self.path, e);
+                None
+            }
+        }
+    }
+}
+
+// Entry read back from the disk, see 'Evicted::read'.
+pub struct Restored {
+    key: String,
+    value: Cached,
+    size: u64,
+}
+
+fn restore(lru: &mut Lru, entry: Restored) {
+    let kind = entry.value.kind();
+    // Note(andrew): Somebody might have stored the newer result in the meantime, which is not overwritten.
+    if lru.peek(&(kind, entry.key.clone())).is_none() {
+        lru.insert(entry.key, entry.value, entry.size);
+        CACHE_RELOADS.with_label_values(&[kind.label()]).inc();
+    }
+}
+
+// Note(andrew): File-backed storage, which keeps every entry as a separate json file in the given
+//     directory, so the cache survives restarts. Entries are loaded into memory on startup (as many as
+//     the memory budget allows, most recently written ones first), and every change is written through
+//     to the disk immediately (outside of the lock, see 'Disk'), which means reads are as fast as with
+//     'MemoryStorage', and we only pay for the disk on writes (new analysis or verification). Entries
+//     evicted from memory (see 'Lru') are still on the disk, and are read back before the next use (see
+//     'Evicted').  @Speed
+//
+//     Activity reports are kept in the 'activity' subdirectory.
+pub struct FileStorage {
+    lru: Lru,
+    data_dir: PathBuf,
+    activity_dir: PathBuf,
+}
+
+impl FileStorage {
+    pub fn open(dir: &Path, budget: u64) -> Result<Self, String> {
+        let mut storage = Self {
+            lru: Lru::new(budget),
+            data_dir: dir.to_path_buf(),
+            activity_dir: dir.join("activity"),
+        };
+        storage.load(Kind::Data)?;
+        storage.load(Kind::Activity)?;
+        Ok(storage)
+    }
+
+    fn dir(&self, kind: Kind) -> &Path {
+        match kind {
+            Kind::Data => &self.data_dir,
+            Kind::Activity => &self.activity_dir,
+        }
+    }
+
+    fn read(kind: Kind, path: &Path) -> Result<(String, Cached), String> {
+        match kind {
+            Kind::Data => read_entry::<Data>(path).map(|mut entry| {
+                let verified = read_verified(path).unwrap_or_default();
+                entry.data.verified_time = entry.data.verified_time.max(verified);
+                (entry.key, Cached::Data(entry.data))
+            }),
+            Kind::Activity => read_entry::<Activity>(path).map(|entry| (entry.key, Cached::Activity(entry.data))),
+        }
+    }
+
+    fn load(&mut self, kind: Kind) -> Result<(), String> {
+        let dir = self.dir(kind).to_path_buf();
+        if let Err(e) = fs::create_dir_all(&dir) {
+            return Err(format!("Failed to create storage directory {:?}: {}", dir, e));
+        }
+
+        let files = match fs::read_dir(&dir) {
+            Ok(value) => value,
+            Err(e) => return Err(format!("Failed to read storage directory {:?}: {}", dir, e)),
+        };
+
+        // Skipping temporary and unrelated files (and directories).
+        let mut files: Vec<(SystemTime, u64, PathBuf)> = files
+            .flatten()
+            .map(|file| file.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .filter_map(|path| {
+                let meta = fs::metadata(&path).ok()?;
+                Some((meta.modified().unwrap_or(SystemTime::UNIX_EPOCH), meta.len(), path))
+            })
+            .collect();
+        files.sort();
+
+        let mut count = 0;
+        for (_, size, path) in files {
+            // Note(andrew): Broken entry is not a reason to refuse to start, since this is only a cache, so
+            //     we are just skipping it (it will be overwritten by the next analysis of the same key).
+            match Self::read(kind, &path) {
+                Ok((key, value)) => {
+                    self.lru.insert(key, value, size);
+                    count += 1;
+                }
+                Err(e) => info!("Skipping invalid storage entry {:?}: {}", &path, e),
+            };
+        }
+
+        info!(
+            "Loaded {} entries from storage at {:?} ({} kept in memory)",
+            count,
+            dir,
+            self.lru.count(kind)
+        );
+        Ok(())
+    }
+
+    fn lookup(&mut self, key: &Key) -> Option<&Cached> {
+        self.lru.touch(key);
+        self.lru.peek(key)
+    }
+}
+
+impl Storage for FileStorage {
+    fn get(&mut self, key: &str) -> Option<&Data> {
+        match self.lookup(&(Kind::Data, key.to_string())) {
+            Some(Cached::Data(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn peek(&self, key: &str) -> Option<&Data> {
+        match self.lru.peek(&(Kind::Data, key.to_string())) {
+            Some(Cached::Data(data)) => Some(data),
+            _ => None,
+        }
+    }
+
+    fn insert(&mut self, key: String, data: Data, size: u64) {
+        self.lru.insert(key, Cached::Data(data), size);
+    }
+
+    fn set_verified_time(&mut self, key: &str, time: u64) {
+        if let Some(Cached::Data(data)) = self.lru.peek_mut(&(Kind::Data, key.to_string())) {
+            data.verified_time = time;
+        }
+    }
+
+    fn disk(&self) -> Option<Disk> {
+        Some(Disk {
+            data_dir: self.data_dir.clone(),
+            activity_dir: self.activity_dir.clone(),
+        })
+    }
+
+    fn evicted(&self, kind: Kind, key: &str) -> Option<Evicted> {
+        match self.lru.peek(&(kind, key.to_string())) {
+            Some(_) => None,
+            None => Some(Evicted {
+                kind,
+                key: key.to_string(),
+                path: entry_path(self.dir(kind), key),
+            }),
+        }
+    }
+
+    fn restore(&mut self, entry: Restored) {
+        restore(&mut self.lru, entry);
+    }
+
+    fn len(&self) -> usize {
+        self.lru.count(Kind::Data)
+    }
+
+    fn size_bytes(&self) -> u64 {
+        self.lru.bytes
+    }
+
+    fn get_activity(&mut self, key: &str) -> Option<&Activity> {
+        match self.lookup(&(Kind::Activity, key.to_string())) {
+            Some(Cached::Activity(activity)) => Some(activity),
+            _ => None,
+        }
+    }
+
+    fn insert_activity(&mut self, key: String, activity: Activity, size: u64) {
+        self.lru.insert(key, Cached::Activity(activity), size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Info;
+
+    fn activity() -> Cached {
+        Cached::Activity(Activity::new("repo".to_string(), "HEAD".to_string()))
+    }
+
+    fn has(lru: &Lru, key: &str) -> bool {
+        lru.peek(&(Kind::Activity, key.to_string())).is_some()
+    }
+
+    #[test]
+    fn lru_insert_never_evicts_the_inserted_entry() {
+        let mut lru = Lru::new(100);
+        lru.insert("a".to_string(), activity(), 40);
+        lru.insert("b".to_string(), activity(), 40);
+        lru.touch(&(Kind::Activity, "a".to_string()));
+
+        // Least recently used one goes first, and only as many as needed to fit into the budget.
+        lru.insert("c".to_string(), activity(), 40);
+        assert!(has(&lru, "a") && !has(&lru, "b") && has(&lru, "c"));
+        assert_eq!(lru.bytes, 80);
+
+        // Entry which doesn't fit into the budget on its own is still kept, with everything else evicted.
+        lru.insert("d".to_string(), activity(), 500);
+        assert!(!has(&lru, "a") && !has(&lru, "c") && has(&lru, "d"));
+        assert_eq!(lru.bytes, 500);
+
+        // Same for the entry which is replaced by the bigger one.
+        lru.insert("d".to_string(), activity(), 600);
+        assert!(has(&lru, "d"));
+        assert_eq!(lru.bytes, 600);
+    }
+
+    #[test]
+    fn lru_without_budget_never_evicts() {
+        let mut lru = Lru::new(0);
+        for key in ["a", "b", "c"] {
+            lru.insert(key.to_string(), activity(), u32::MAX as u64);
+        }
+        assert_eq!(lru.count(Kind::Activity), 3);
+    }
+
+    #[test]
+    fn file_storage_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = "https://github.com/me/proj@HEAD";
+        let mut data = Data::new("me/proj".to_string(), "HEAD".to_string(), Info::new(3, 2, 1));
+        data.hash = "6e179ba3b9df125de3d780bf19ef261926ed82b0".to_string();
+        let creation_time = data.creation_time;
+
+        {
+            let mut storage = FileStorage::open(dir.path(), 0).unwrap();
+            let disk = storage.disk().unwrap();
+            let size = prepare(Some(&disk), Kind::Data, key, &data);
+            storage.insert(key.to_string(), data, size);
+            storage.set_verified_time(key, creation_time + 100);
+            disk.write_verified(key, creation_time + 100);
+        }
+
+        // Entry itself still has the time of the analysis, but the newer one of the '.verified' file wins.
+        let mut storage = FileStorage::open(dir.path(), 0).unwrap();
+        let data = storage.get(key).unwrap();
+        assert_eq!(data.creation_time, creation_time);
+        assert_eq!(data.verified_time, creation_time + 100);
+        assert_eq!(data.hash, "6e179ba3b9df125de3d780bf19ef261926ed82b0");
+
+        // And the older one loses.
+        storage.disk().unwrap().write_verified(key, creation_time - 100);
+        let mut storage = FileStorage::open(dir.path(), 0).unwrap();
+        assert_eq!(storage.get(key).unwrap().verified_time, creation_time);
+    }
+}
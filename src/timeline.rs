@@ -0,0 +1,174 @@
+use rocket::serde::json::{Value, json};
+use rocket::serde::{Deserialize, Serialize};
+
+use crate::error::KloccError;
+use crate::storage::Storage;
+
+// Note(andrew): Every sample is a full analysis of the repository at that commit, so the amount of them is
+//     limited, to keep a single timeline request from occupying the worker for hours.
+const MAX_SAMPLES: u32 = 100;
+
+fn default_samples() -> u32 {
+    10
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum Period {
+    Week,
+    Month,
+}
+
+impl Period {
+    fn seconds(&self) -> u64 {
+        match self {
+            Period::Week => 60 * 60 * 24 * 7,
+            Period::Month => 60 * 60 * 24 * 30, // Close enough for sampling.
+        }
+    }
+}
+
+// Note(andrew): How to pick commits for the timeline from the first-parent history of the target ref,
+//     going back from its tip. Either every K-th commit ('every'), or one commit per 'period', where
+//     commits are picked at least a period apart. When neither is given, the latest commits are taken.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TimelineOptions {
+    pub every: Option<u32>,
+    pub period: Option<Period>,
+    // Maximum amount of commits to count (see 'MAX_SAMPLES').
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+}
+
+impl TimelineOptions {
+    pub fn validate(self) -> Result<Self, KloccError> {
+        if self.every.is_some() && self.period.is_some() {
+            let msg = "Timeline can be sampled either by 'every' or by 'period', not both.".to_string();
+            return Err(KloccError::InvalidOptions(msg)); // Early return.
+        }
+        if self.every == Some(0) {
+            let msg = "Timeline 'every' has to be at least 1.".to_string();
+            return Err(KloccError::InvalidOptions(msg)); // Early return.
+        }
+        if self.samples == 0 || self.samples > MAX_SAMPLES {
+            let msg = format!("Timeline 'samples' has to be between 1 and {}.", MAX_SAMPLES);
+            return Err(KloccError::InvalidOptions(msg)); // Early return.
+        }
+
+        Ok(self)
+    }
+}
+
+// Commit of the repository history, with the commit time (unix timestamp, in seconds).
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Commit {
+    pub hash: String,
+    pub time: u64,
+}
+
+// Commit picked for the timeline, along with the cache key of its analysis result.
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub commit: Commit,
+    pub key: String,
+}
+
+// Pick commits for the timeline from the 'history', which is expected to go from the newest commit to the
+// oldest one (like 'git log' does). Picked commits are returned from the oldest to the newest one, since
+// that's the natural order of the time series.
+pub fn pick(history: &[Commit], options: &TimelineOptions) -> Vec<Commit> {
+    let limit = options.samples as usize;
+    let mut picked: Vec<Commit> = Vec::new();
+
+    match (options.every, options.period) {
+        (_, Some(period)) => {
+            for commit in history {
+                if picked.len() >= limit {
+                    break;
+                }
+                // Note(andrew): Commit times are not guaranteed to go down along the history (clocks and
+                //     rebases are fun), so we only take commits that are far enough from the last picked one.
+                match picked.last() {
+                    Some(last) if commit.time + period.seconds() > last.time => continue,
+                    _ => picked.push(commit.clone()),
+                }
+            }
+        }
+        (every, None) => {
+            let step = every.unwrap_or(1).max(1) as usize;
+            picked.extend(history.iter().step_by(step).take(limit).cloned());
+        }
+    }
+
+    picked.reverse();
+    picked
+}
+
+// Build the time series from the analysis results of the samples. Samples that are no longer in the cache
+// are skipped, since the rest of the series is still meaningful.
+pub fn series(storage: &mut dyn Storage, samples: &[Sample]) -> Value {
+    let points: Vec<Value> = samples
+        .iter()
+        .filter_map(|sample| {
+            let data = storage.get(&sample.key)?;
+            let languages: Vec<Value> = data
+                .languages
+                .iter()
+                .map(|lang| json!({"name": lang.name, "total": lang.total}))
+                .collect();
+
+            Some(json!({
+                "hash": sample.commit.hash, "time": sample.commit.time,
+                "total": data.total, "languages": languages,
+            }))
+        })
+        .collect();
+
+    json!({ "samples": points })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: u64 = 60 * 60 * 24;
+
+    fn history(commits: &[(&str, u64)]) -> Vec<Commit> {
+        commits
+            .iter()
+            .map(|(hash, days)| Commit {
+                hash: hash.to_string(),
+                time: days * DAY,
+            })
+            .collect()
+    }
+
+    fn hashes(commits: &[Commit]) -> Vec<&str> {
+        commits.iter().map(|commit| commit.hash.as_str()).collect()
+    }
+
+    #[test]
+    fn pick_by_period_skips_out_of_order_commits() {
+        // Newest first, where 'c' is newer than the tip, and 'f' is newer than 'e' (i.e. skewed clocks).
+        let history = history(&[
+            ("a", 100),
+            ("b", 98),
+            ("c", 110),
+            ("d", 92),
+            ("e", 85),
+            ("f", 86),
+            ("g", 70),
+        ]);
+        let mut options = TimelineOptions {
+            every: None,
+            period: Some(Period::Week),
+            samples: 10,
+        };
+        assert_eq!(hashes(&pick(&history, &options)), vec!["g", "e", "d", "a"]);
+
+        options.samples = 2;
+        assert_eq!(hashes(&pick(&history, &options)), vec!["d", "a"]);
+    }
+}
@@ -0,0 +1,117 @@
+use rocket::serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+
+use crate::data::{Data, FileInfo, Info};
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct LanguageTotal {
+    pub name: String,
+    pub total: Info,
+}
+
+// Note(andrew): Node of the directory tree of the analysis result, where every directory carries the lines
+//     of all files inside of it (no matter how deep), in total and per language. Files are the leaves of the
+//     tree, and directories at the depth limit have no children, but still include all of their lines.
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TreeNode {
+    pub name: String,
+    pub path: String,
+    // Either 'directory' or 'file'.
+    pub kind: &'static str,
+    pub total: Info,
+    pub languages: Vec<LanguageTotal>,
+    pub children: Vec<TreeNode>,
+}
+
+#[derive(Default)]
+struct Builder {
+    file: bool,
+    languages: BTreeMap<String, Info>,
+    children: BTreeMap<String, Builder>,
+}
+
+impl Builder {
+    fn add(&mut self, language: &str, file: &FileInfo) {
+        let info = self.languages.entry(language.to_string()).or_default();
+        info.code += file.code;
+        info.comments += file.comments;
+        info.blanks += file.blanks;
+    }
+
+    fn build(self, name: String, path: String) -> TreeNode {
+        let mut total = Info::default();
+        let mut languages: Vec<LanguageTotal> = self
+            .languages
+            .into_iter()
+            .map(|(name, info)| {
+                total.code += info.code;
+                total.comments += info.comments;
+                total.blanks += info.blanks;
+                LanguageTotal { name, total: info }
+            })
+            .collect();
+
+        let mut children: Vec<TreeNode> = self
+            .children
+            .into_iter()
+            .map(|(child, builder)| {
+                let child_path = if path.is_empty() {
+                    child.clone()
+                } else {
+                    format!("{}/{}", path, child)
+                };
+                builder.build(child, child_path)
+            })
+            .collect();
+        // Biggest entries go first, same as for the languages and files of the 'Data'.
+        children.sort_by_key(|c| Reverse(lines(&c.total)));
+        languages.sort_by_key(|l| Reverse(lines(&l.total)));
+
+        TreeNode {
+            name,
+            path,
+            kind: if self.file { "file" } else { "directory" },
+            total,
+            languages,
+            children,
+        }
+    }
+}
+
+fn lines(info: &Info) -> u64 {
+    (info.code as u64) + (info.comments as u64) + (info.blanks as u64)
+}
+
+// Build the directory tree of the analysis result, down to the given depth (where 0 is only the root of the
+// repository, and 1 is its top-level files and directories). Embedded code is attributed to the files it is
+// embedded into, under its own language, unless it was excluded from the total (see 'CountOptions').
+pub fn build(data: &Data, depth: u32) -> TreeNode {
+    let mut root = Builder::default();
+
+    let mut files: Vec<(&str, &FileInfo)> = Vec::new();
+    for lang in data.languages.iter() {
+        files.extend(lang.files.iter().map(|file| (lang.name.as_str(), file)));
+        if data.options.include_embedded {
+            for child in lang.children.iter() {
+                files.extend(child.files.iter().map(|file| (child.name.as_str(), file)));
+            }
+        }
+    }
+
+    for (language, file) in files {
+        root.add(language, file);
+
+        let parts: Vec<&str> = file.path.split('/').collect();
+        let mut node = &mut root;
+        for (i, part) in parts.iter().enumerate().take(depth as usize) {
+            node = node.children.entry(part.to_string()).or_default();
+            node.file = i + 1 == parts.len();
+            node.add(language, file);
+        }
+    }
+
+    root.build(".".to_string(), "".to_string())
+}
@@ -1,13 +1,20 @@
-// This function takes service name, and other useful arguments, and expands them into
-// a valid url for git repository for specific service provider. In theory, we could
-// just allow passing url, but it's questionable decision from the security standpoint.
-pub fn expand_url(service: &String, username: &String, reponame: &String) -> Result<String, String> {
-    match service.as_str() {
-        "github" => Ok(format!("https://github.com/{}/{}.git", username, reponame)),
-        "gitlab" => Ok(format!("https://gitlab.com/{}/{}.git", username, reponame)),
-        _ => Err(format!(
-            "Service provider for git with a name '{}' is not supported!",
-            service
-        )),
-    }
+use std::sync::atomic::AtomicBool;
+
+// Note(andrew): Logs go to stdout for the server, but command line mode (see 'cli') prints its result
+//     there, so it switches logging to stderr, where it doesn't get mixed up with the result.
+pub static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// Logging with current unix timestamp. Useful to reduce number of typed lines to do basic logging.
+macro_rules! info {
+    ( $s:tt, $( $x:expr ),* ) => {
+        {
+            let start_time = std::time::SystemTime::now();
+            let curr_time  = start_time.duration_since(std::time::UNIX_EPOCH).unwrap();  // @UnsafeUnwrap
+            let msg = format!($s, $( $x, )*);
+            match crate::utils::LOG_TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+                true => eprintln!("[{:>10}] - {}", curr_time.as_secs(), &msg),
+                false => println!("[{:>10}] - {}", curr_time.as_secs(), &msg),
+            }
+        }
+    };
 }